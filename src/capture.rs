@@ -0,0 +1,156 @@
+use chrono::{DateTime, Local};
+use flate2::bufread::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Endpoint {
+    StopPassages,
+    TripPassages,
+}
+
+/// Single upstream response as stored in a capture file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CaptureRecord {
+    pub fetched_at: DateTime<Local>,
+    pub endpoint: Endpoint,
+    /// Stop point or trip id the request was made for.
+    pub id: String,
+    pub status: u16,
+    pub body: String,
+}
+
+/// Appends gzip-compressed JSON lines to a capture file.
+///
+/// Every record is a gzip member of its own, so a killed recorder loses at
+/// most the record it was writing, and later runs can append to the file.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn open(path: &str) -> io::Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        println!("Recording upstream responses to {}", path);
+
+        Ok(Recorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, record: &CaptureRecord) {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let result = serde_json::to_writer(&mut encoder, record)
+            .map_err(io::Error::from)
+            .and_then(|_| encoder.write_all(b"\n"))
+            .and_then(|_| encoder.finish())
+            .and_then(|member| self.file.lock().unwrap().write_all(&member));
+
+        if let Err(e) = result {
+            println!(
+                "Failed to record {:?} {}: {}",
+                record.endpoint, record.id, e
+            );
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 0x08];
+
+/// Reads all records of a capture file in file order.
+///
+/// Damaged data, such as a member cut short by a killed recorder, is skipped
+/// up to the next gzip member.
+pub fn read_capture(path: &str) -> io::Result<Vec<CaptureRecord>> {
+    let data = fs::read(path)?;
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let mut decoder = GzDecoder::new(&data[offset..]);
+        let mut content = String::new();
+        let result = decoder.read_to_string(&mut content);
+        let rest = decoder.into_inner().len();
+
+        // Lines decoded before an error are still good.
+        for (n, line) in content.lines().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(record) => records.push(record),
+                Err(e) => println!(
+                    "Skipping capture {} line {} of member at byte {}: {}",
+                    path,
+                    n + 1,
+                    offset,
+                    e
+                ),
+            }
+        }
+
+        offset = match result {
+            Ok(_) => data.len() - rest,
+            Err(e) => {
+                let next = data[offset + 1..]
+                    .windows(GZIP_MAGIC.len())
+                    .position(|w| w == GZIP_MAGIC)
+                    .map_or(data.len(), |i| offset + 1 + i);
+                println!(
+                    "Skipping damaged capture {} bytes {}..{}: {}",
+                    path, offset, next, e
+                );
+                next
+            }
+        };
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_dir::TestDir;
+
+    fn record(id: &str) -> CaptureRecord {
+        CaptureRecord {
+            fetched_at: Local::now(),
+            endpoint: Endpoint::StopPassages,
+            id: String::from(id),
+            status: 200,
+            body: String::from("{}"),
+        }
+    }
+
+    #[test]
+    fn runs_after_a_killed_recorder_are_still_read() {
+        let dir = TestDir::new();
+        let path = &dir.path("capture.gz");
+
+        let recorder = Recorder::open(path).unwrap();
+        recorder.record(&record("1"));
+        recorder.record(&record("2"));
+        drop(recorder);
+
+        // A member cut short, as left by a kill mid-write.
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, &record("lost")).unwrap();
+        let member = encoder.finish().unwrap();
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&member[..member.len() / 2]).unwrap();
+
+        Recorder::open(path).unwrap().record(&record("3"));
+
+        let ids: Vec<_> = read_capture(path)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+    }
+}
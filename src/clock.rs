@@ -0,0 +1,68 @@
+use chrono::Utc;
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::timestamp::{Timestamp, TIMEZONE};
+
+/// Time source used by the pairing logic, so that live polling and capture
+/// replay measure fragments the same way.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timestamp;
+
+    fn instant(&self) -> Instant;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Utc::now().with_timezone(&TIMEZONE)
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// Clock that only moves when told to.
+pub struct SimulatedClock {
+    base_instant: Instant,
+    base: Timestamp,
+    now: Mutex<Timestamp>,
+}
+
+impl SimulatedClock {
+    pub fn new(start: Timestamp) -> SimulatedClock {
+        SimulatedClock {
+            base_instant: Instant::now(),
+            base: start,
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn set(&self, now: Timestamp) {
+        let mut current = self.now.lock().unwrap();
+        if now > *current {
+            *current = now;
+        }
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Timestamp {
+        *self.now.lock().unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        let elapsed = (self.now() - self.base).to_std().unwrap_or_default();
+
+        self.base_instant + elapsed
+    }
+}
@@ -0,0 +1,108 @@
+use serde::Deserialize;
+
+use crate::history::HistoryConfig;
+use crate::poll_plan::PollingConfig;
+use crate::snapshot::SnapshotConfig;
+use crate::storage::StorageConfig;
+use crate::trip_registry::TripConfig;
+use crate::ttss::ClientConfig;
+
+use std::fs;
+use std::io;
+
+const DEFAULT_CONFIG_PATH: &str = "mpkflow.toml";
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    #[serde(rename = "corridor")]
+    pub corridors: Vec<Corridor>,
+    #[serde(default)]
+    pub ttss: ClientConfig,
+    #[serde(default)]
+    pub polling: PollingConfig,
+    #[serde(default)]
+    pub measurement: Measurement,
+    #[serde(default)]
+    pub trips: TripConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+}
+
+/// Which observations fragment times are measured from.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Measurement {
+    /// Departures seen in stop passages.
+    #[default]
+    Stop,
+    /// Stop-by-stop progress of trips discovered at the monitored stops.
+    Trip,
+    Combined,
+}
+
+impl Measurement {
+    pub fn uses_stops(self) -> bool {
+        self != Measurement::Trip
+    }
+
+    pub fn uses_trips(self) -> bool {
+        self != Measurement::Stop
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Corridor {
+    pub name: String,
+    pub stops: Vec<StopPoint>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StopPoint {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> io::Result<Config> {
+        let content = fs::read_to_string(path)?;
+
+        let config: Config =
+            toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let retention = &config.storage.retention;
+        let positive = [
+            ("storage.retention.bucket_secs", retention.bucket_secs),
+            (
+                "storage.retention.compaction_interval_secs",
+                retention.compaction_interval_secs,
+            ),
+            ("snapshot.interval_secs", config.snapshot.interval_secs),
+        ];
+        if let Some((name, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} must be positive", name),
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// Reads config from path given in `MPKFLOW_CONFIG`, falling back to `mpkflow.toml`.
+    pub fn from_env() -> io::Result<Config> {
+        let path =
+            std::env::var("MPKFLOW_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+
+        Config::load(&path)
+    }
+
+    pub fn stop_ids(&self) -> impl Iterator<Item = &str> {
+        self.corridors
+            .iter()
+            .flat_map(|c| c.stops.iter())
+            .map(|s| s.id.as_str())
+    }
+}
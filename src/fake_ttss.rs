@@ -0,0 +1,423 @@
+use actix_web::{web, web::Data, HttpResponse};
+use chrono::{NaiveTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::timestamp::TIMEZONE;
+
+/// How long a departed passage stays in the `old` list of a stop.
+const OLD_PASSAGE_WINDOW_SECS: i64 = 10 * 60;
+/// How far ahead upcoming passages are announced in `actual`.
+const PASSAGE_HORIZON_SECS: i64 = 30 * 60;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Scenario {
+    /// Wall clock time corresponding to simulated second 0.
+    pub start_time: NaiveTime,
+    /// Simulated seconds per real second, 0 freezes the clock.
+    #[serde(default)]
+    pub speed: i64,
+    pub stops: Vec<ScenarioStop>,
+    #[serde(rename = "tram")]
+    pub trams: Vec<ScenarioTram>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScenarioStop {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScenarioTram {
+    pub trip_id: String,
+    pub route: String,
+    pub direction: String,
+    pub vehicle_id: Option<String>,
+    /// Simulated second of departure from the first stop.
+    pub departure: i64,
+    /// Travel time of each consecutive fragment, the last value repeats.
+    pub segment_secs: Vec<i64>,
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> io::Result<Scenario> {
+        let content = fs::read_to_string(path)?;
+
+        toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl ScenarioTram {
+    fn departure_at(&self, stop_idx: usize) -> i64 {
+        let last = self.segment_secs.last().cloned().unwrap_or(0);
+
+        self.departure
+            + (0..stop_idx)
+                .map(|i| self.segment_secs.get(i).cloned().unwrap_or(last))
+                .sum::<i64>()
+    }
+}
+
+#[derive(Clone)]
+pub struct SimClock {
+    start: Instant,
+    speed: i64,
+    offset: Arc<AtomicI64>,
+}
+
+impl SimClock {
+    pub fn new(speed: i64) -> SimClock {
+        SimClock {
+            start: Instant::now(),
+            speed,
+            offset: Arc::new(AtomicI64::new(0)),
+        }
+    }
+
+    pub fn now(&self) -> i64 {
+        self.offset.load(Ordering::SeqCst) + self.start.elapsed().as_secs() as i64 * self.speed
+    }
+
+    #[cfg(test)]
+    pub fn advance(&self, secs: i64) {
+        self.offset.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone)]
+pub struct FakeTtss {
+    scenario: Arc<Scenario>,
+    pub clock: SimClock,
+}
+
+impl FakeTtss {
+    pub fn new(scenario: Scenario) -> FakeTtss {
+        FakeTtss {
+            clock: SimClock::new(scenario.speed),
+            scenario: Arc::new(scenario),
+        }
+    }
+
+    fn clock_time(&self, sim_secs: i64) -> String {
+        (self.scenario.start_time + chrono::Duration::seconds(sim_secs))
+            .format("%H:%M")
+            .to_string()
+    }
+
+    fn epoch_millis(&self, sim_secs: i64) -> i64 {
+        let start = Utc::today()
+            .with_timezone(&TIMEZONE)
+            .and_time(self.scenario.start_time)
+            .unwrap();
+
+        (start + chrono::Duration::seconds(sim_secs)).timestamp_millis()
+    }
+
+    pub fn stop_passages(&self, stop_point: &str) -> Option<serde_json::Value> {
+        let stop_idx = self
+            .scenario
+            .stops
+            .iter()
+            .position(|s| s.id == stop_point)?;
+        let stop = &self.scenario.stops[stop_idx];
+        let now = self.clock.now();
+
+        let mut actual = Vec::new();
+        let mut old = Vec::new();
+        for tram in &self.scenario.trams {
+            let departure = tram.departure_at(stop_idx);
+            let relative = departure - now;
+            let passage = json!({
+                "actualRelativeTime": relative,
+                "actualTime": self.clock_time(departure),
+                "direction": tram.direction,
+                "mixedTime": self.clock_time(departure),
+                "passageid": format!("-{}{}", tram.trip_id, stop_idx),
+                "patternText": tram.route,
+                "plannedTime": self.clock_time(departure),
+                "routeId": tram.route,
+                "status": if relative > 0 { "PREDICTED" } else { "DEPARTED" },
+                "tripId": tram.trip_id,
+                "vehicleId": tram.vehicle_id,
+            });
+
+            if relative > 0 && relative <= PASSAGE_HORIZON_SECS {
+                actual.push((relative, passage));
+            } else if relative <= 0 && relative > -OLD_PASSAGE_WINDOW_SECS {
+                old.push((relative, passage));
+            }
+        }
+        actual.sort_by_key(|(relative, _)| *relative);
+        old.sort_by_key(|(relative, _)| -*relative);
+
+        let routes: Vec<_> = self
+            .scenario
+            .trams
+            .iter()
+            .map(|t| {
+                json!({
+                    "alerts": [],
+                    "authority": "MPK",
+                    "directions": [t.direction],
+                    "id": t.route,
+                    "name": t.route,
+                    "routeType": "tram",
+                    "shortName": t.route,
+                })
+            })
+            .collect();
+
+        let first = self.scenario.trams.iter().map(|t| t.departure).min();
+        let last = self
+            .scenario
+            .trams
+            .iter()
+            .map(|t| t.departure_at(self.scenario.stops.len() - 1))
+            .max();
+
+        Some(json!({
+            "actual": actual.into_iter().map(|(_, p)| p).collect::<Vec<_>>(),
+            "directions": [],
+            "firstPassageTime": self.epoch_millis(first.unwrap_or(0)),
+            "generalAlerts": [],
+            "lastPassageTime": self.epoch_millis(last.unwrap_or(0)),
+            "old": old.into_iter().map(|(_, p)| p).collect::<Vec<_>>(),
+            "routes": routes,
+            "stopName": stop.name,
+            "stopShortName": stop.id,
+        }))
+    }
+
+    pub fn trip_passages(&self, trip_id: &str) -> Option<serde_json::Value> {
+        let tram = self.scenario.trams.iter().find(|t| t.trip_id == trip_id)?;
+        let now = self.clock.now();
+
+        let mut actual = Vec::new();
+        let mut old = Vec::new();
+        for (stop_idx, stop) in self.scenario.stops.iter().enumerate() {
+            let departure = tram.departure_at(stop_idx);
+            let status = if departure <= now {
+                "DEPARTED"
+            } else if now < tram.departure {
+                "PLANNED"
+            } else {
+                "PREDICTED"
+            };
+            let passage = json!({
+                "actualTime": self.clock_time(departure),
+                "plannedTime": self.clock_time(departure),
+                "status": status,
+                "stop": {
+                    "id": stop.id,
+                    "name": stop.name,
+                    "shortName": stop.id,
+                },
+                "stop_seq_num": (stop_idx + 1).to_string(),
+            });
+
+            if departure <= now {
+                old.push(passage);
+            } else {
+                actual.push(passage);
+            }
+        }
+
+        Some(json!({
+            "actual": actual,
+            "directionText": tram.direction,
+            "old": old,
+            "routeName": tram.route,
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+struct StopPassagesQuery {
+    #[serde(rename = "stopPoint")]
+    stop_point: String,
+}
+
+#[derive(Deserialize)]
+struct TripPassagesQuery {
+    #[serde(rename = "tripId")]
+    trip_id: String,
+}
+
+async fn handle_stop_passages(
+    query: web::Query<StopPassagesQuery>,
+    fake: Data<FakeTtss>,
+) -> HttpResponse {
+    match fake.stop_passages(&query.stop_point) {
+        Some(body) => HttpResponse::Ok().json(body),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn handle_trip_passages(
+    query: web::Query<TripPassagesQuery>,
+    fake: Data<FakeTtss>,
+) -> HttpResponse {
+    match fake.trip_passages(&query.trip_id) {
+        Some(body) => HttpResponse::Ok().json(body),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Registers the TTSS endpoints; expects `Data<FakeTtss>` in the app.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route(
+        "/services/passageInfo/stopPassages/stopPoint",
+        web::get().to(handle_stop_passages),
+    )
+    .route(
+        "/services/tripInfo/tripPassages",
+        web::get().to(handle_trip_passages),
+    );
+}
+
+/// Single tram crossing three stops: fragments take 60 s and 90 s.
+#[cfg(test)]
+pub fn test_scenario() -> Scenario {
+    toml::from_str(
+        r#"
+        start_time = "12:00:00"
+
+        stops = [
+            { id = "12529", name = "Rondo Mogilskie" },
+            { id = "12919", name = "Rondo Grzegórzeckie" },
+            { id = "13019", name = "Cystersów" },
+        ]
+
+        [[tram]]
+        trip_id = "8059232507169530113"
+        route = "4"
+        direction = "Wzgórza Krzesławickie"
+        vehicle_id = "-1188950295589926069"
+        departure = 30
+        segment_secs = [60, 90]
+        "#,
+    )
+    .unwrap()
+}
+
+/// Single corridor config over the stops of `test_scenario`.
+#[cfg(test)]
+pub fn test_config(base_url: String) -> crate::config::Config {
+    use crate::config::{Config, Corridor, StopPoint};
+    Config {
+        corridors: vec![Corridor {
+            name: String::from("test"),
+            stops: test_scenario()
+                .stops
+                .iter()
+                .map(|s| StopPoint {
+                    id: s.id.clone(),
+                    name: None,
+                })
+                .collect(),
+        }],
+        ttss: crate::ttss::ClientConfig {
+            base_url,
+            scheduler: crate::scheduler::SchedulerConfig {
+                startup_stagger_ms: 0,
+                ..crate::scheduler::SchedulerConfig::default()
+            },
+            ..crate::ttss::ClientConfig::default()
+        },
+        ..Config::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix::prelude::*;
+    use actix_web::{test, App};
+
+    use crate::passage::PassageWelcome;
+    use crate::ttss::{ReqwestClient, TtssClient};
+    use crate::Welcome;
+
+    #[test]
+    fn stop_passages_split_into_actual_and_old() {
+        let fake = FakeTtss::new(test_scenario());
+        fake.clock.advance(100);
+
+        let welcome: Welcome =
+            serde_json::from_value(fake.stop_passages("12919").unwrap()).unwrap();
+        assert_eq!(welcome.actual.len(), 0);
+        assert_eq!(welcome.old[0].actual_relative_time, -10);
+
+        let welcome: Welcome =
+            serde_json::from_value(fake.stop_passages("13019").unwrap()).unwrap();
+        assert_eq!(welcome.old.len(), 0);
+        assert_eq!(welcome.actual[0].actual_relative_time, 80);
+        assert_eq!(welcome.stop_name, "Cystersów");
+
+        assert!(fake.stop_passages("1").is_none());
+    }
+
+    #[test]
+    fn trip_passages_follow_simulated_clock() {
+        let fake = FakeTtss::new(test_scenario());
+        fake.clock.advance(100);
+
+        let value = fake.trip_passages("8059232507169530113").unwrap();
+        assert_eq!(value["old"].as_array().unwrap().len(), 2);
+        assert_eq!(value["actual"][0]["stop"]["id"], "13019");
+        serde_json::from_value::<PassageWelcome>(value).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn tram_passing_corridor_yields_fragment_times() {
+        let fake = FakeTtss::new(test_scenario());
+        let server_fake = fake.clone();
+        let srv = test::start(move || App::new().data(server_fake.clone()).configure(configure));
+
+        let config = test_config(format!("http://{}", srv.addr()));
+        let client: Arc<dyn TtssClient> = Arc::new(ReqwestClient::new(&config.ttss).unwrap());
+        let stops = crate::spawn_stop_pollers(&config, Some(client), crate::clock::system());
+        actix_rt::time::delay_for(std::time::Duration::from_millis(500)).await;
+
+        fake.clock.advance(200);
+        for (_, stop) in &stops {
+            stop.do_send(crate::UpdateRequest {});
+        }
+        actix_rt::time::delay_for(std::time::Duration::from_millis(500)).await;
+
+        let mut app = test::init_service(
+            App::new()
+                .data(crate::route_fragment_registry::RouteFragmentRegistry::from_registry())
+                .data(config)
+                .route("/stats.json", web::get().to(crate::handle_frag_stat))
+                .route(
+                    "/fragments/{id}/lines",
+                    web::get().to(crate::handle_fragment_lines),
+                ),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/stats.json").to_request();
+        let stats: Vec<serde_json::Value> = test::read_response_json(&mut app, req).await;
+
+        let times: Vec<_> = stats.iter().map(|s| s["time"].as_u64()).collect();
+        assert!(matches!(times[0], Some(59..=60)), "{:?}", times);
+        assert!(matches!(times[1], Some(89..=90)), "{:?}", times);
+        assert_eq!(times.len(), 2);
+
+        let req = test::TestRequest::get()
+            .uri("/fragments/12529-12919/lines")
+            .to_request();
+        let lines: Vec<serde_json::Value> = test::read_response_json(&mut app, req).await;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["line"]["route"], "4");
+        assert_eq!(lines[0]["line"]["direction"], "Wzgórza Krzesławickie");
+    }
+}
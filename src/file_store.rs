@@ -0,0 +1,367 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::passage::PassageStatus;
+use crate::retention::Aggregate;
+use crate::route_fragment::{Edge, Sample};
+use crate::storage::{self, RecordKey, SampleRecord, SampleStore};
+use crate::timestamp::{self, Timestamp};
+use crate::trajectory::Trajectory;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    Jsonl,
+    Csv,
+}
+
+impl FileFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            FileFormat::Jsonl => "jsonl",
+            FileFormat::Csv => "csv",
+        }
+    }
+}
+
+/// Appends samples and finished trips to files in a directory, meant for
+/// archival. Updated samples are appended again and the last record of a
+/// trip wins when reading; files are only rewritten by compaction.
+///
+/// Saving a record identical to the one last written for its trip appends
+/// nothing, as long as that trip is among the recently written ones.
+pub struct FileStore {
+    format: FileFormat,
+    samples: Mutex<Appender>,
+    /// Record last appended for each recently saved trip.
+    written: Mutex<HashMap<RecordKey, SampleRecord>>,
+    trajectories: Mutex<Appender>,
+    aggregates: Mutex<Appender>,
+}
+
+/// Number of trips whose last written record is remembered; the memory is
+/// forgotten as a whole once it grows past this.
+const WRITTEN_CAPACITY: usize = 16384;
+
+impl FileStore {
+    pub fn open(dir: &str, format: FileFormat) -> io::Result<FileStore> {
+        fs::create_dir_all(dir)?;
+        let open = |name: &str| {
+            let path = Path::new(dir).join(format!("{}.{}", name, format.extension()));
+            Appender::open(path, format).map(Mutex::new)
+        };
+
+        Ok(FileStore {
+            format,
+            samples: open("samples")?,
+            written: Mutex::new(HashMap::new()),
+            trajectories: open("trajectories")?,
+            aggregates: open("aggregates")?,
+        })
+    }
+
+    fn read_samples(&self) -> io::Result<Vec<SampleRecord>> {
+        let records = storage::latest_records(self.samples.lock().unwrap().read()?);
+        Ok(records.into_values().collect())
+    }
+
+    fn read_aggregates(&self) -> io::Result<Vec<Aggregate>> {
+        let aggregates: HashMap<_, _> = self
+            .aggregates
+            .lock()
+            .unwrap()
+            .read::<Aggregate>()?
+            .into_iter()
+            .map(|a| (a.key(), a))
+            .collect();
+        Ok(aggregates.into_values().collect())
+    }
+}
+
+impl SampleStore for FileStore {
+    fn save_sample(&self, record: &SampleRecord) -> io::Result<()> {
+        let mut samples = self.samples.lock().unwrap();
+        let mut written = self.written.lock().unwrap();
+        if written.get(&record.key()) == Some(record) {
+            return Ok(());
+        }
+
+        samples.append(record)?;
+        if written.len() >= WRITTEN_CAPACITY {
+            written.clear();
+        }
+        written.insert(record.key(), record.clone());
+        Ok(())
+    }
+
+    fn load_samples(&self, edge: &Edge, since: Timestamp, limit: usize) -> io::Result<Vec<Sample>> {
+        let records = self.read_samples()?;
+        Ok(storage::newest_samples(records.iter(), edge, since, limit))
+    }
+
+    fn edges(&self) -> io::Result<Vec<Edge>> {
+        Ok(storage::distinct_edges(self.read_samples()?.iter()))
+    }
+
+    fn save_trajectory(&self, trajectory: &Trajectory) -> io::Result<()> {
+        let mut trajectories = self.trajectories.lock().unwrap();
+        match self.format {
+            FileFormat::Jsonl => trajectories.append(trajectory),
+            // One row per stop, as CSV has no nesting.
+            FileFormat::Csv => trajectory.stops.iter().try_for_each(|stop| {
+                trajectories.append(&TrajectoryRow {
+                    trip_id: &trajectory.trip_id,
+                    route_name: trajectory.route_name.as_deref(),
+                    direction: trajectory.direction.as_deref(),
+                    vehicle_id: trajectory.vehicle_id.as_deref(),
+                    stop_id: &stop.stop_id,
+                    stop_name: &stop.stop_name,
+                    seq: stop.seq,
+                    planned_time: stop.planned_time,
+                    actual_time: stop.actual_time,
+                    status: stop.status,
+                    delay_secs: stop.delay_secs,
+                })
+            }),
+        }
+    }
+
+    fn samples_between(
+        &self,
+        edge: &Edge,
+        from: Option<Timestamp>,
+        to: Timestamp,
+    ) -> io::Result<Vec<SampleRecord>> {
+        let records = self.read_samples()?;
+        Ok(storage::records_between(records.iter(), edge, from, to))
+    }
+
+    fn samples_before(&self, before: Timestamp) -> io::Result<Vec<SampleRecord>> {
+        let records = self.read_samples()?;
+        Ok(storage::records_before(records.iter(), before))
+    }
+
+    fn delete_samples_before(&self, before: Timestamp) -> io::Result<usize> {
+        let mut samples = self.samples.lock().unwrap();
+        let records: Vec<SampleRecord> = samples.read()?;
+        let mut kept: Vec<_> = storage::latest_records(records).into_values().collect();
+        let count = kept.len();
+        kept.retain(|r| r.leave_time >= before.timestamp());
+        kept.sort_by_key(|r| r.leave_time);
+
+        let deleted = count - kept.len();
+        if deleted > 0 {
+            samples.rewrite(&kept)?;
+            self.written
+                .lock()
+                .unwrap()
+                .retain(|_, r| r.leave_time >= before.timestamp());
+        }
+        Ok(deleted)
+    }
+
+    fn delete_sample(&self, record: &SampleRecord) -> io::Result<()> {
+        let mut samples = self.samples.lock().unwrap();
+        let records: Vec<SampleRecord> = samples.read()?;
+        let mut latest = storage::latest_records(records);
+        self.written.lock().unwrap().remove(&record.key());
+        if latest.remove(&record.key()).is_none() {
+            return Ok(());
+        }
+
+        let mut kept: Vec<_> = latest.into_values().collect();
+        kept.sort_by_key(|r| r.leave_time);
+        samples.rewrite(&kept)
+    }
+
+    fn save_aggregates(&self, aggregates: &[Aggregate]) -> io::Result<()> {
+        let mut appender = self.aggregates.lock().unwrap();
+        aggregates.iter().try_for_each(|a| appender.append(a))
+    }
+
+    fn aggregates_between(
+        &self,
+        edge: &Edge,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> io::Result<Vec<Aggregate>> {
+        let aggregates = self.read_aggregates()?;
+        Ok(storage::aggregates_between(
+            aggregates.iter(),
+            edge,
+            from,
+            to,
+        ))
+    }
+
+    fn delete_aggregates_before(&self, before: Timestamp) -> io::Result<usize> {
+        let mut kept = self.read_aggregates()?;
+        let count = kept.len();
+        kept.retain(|a| a.bucket_start >= before.timestamp());
+        kept.sort_by_key(|a| a.bucket_start);
+
+        let deleted = count - kept.len();
+        if deleted > 0 {
+            self.aggregates.lock().unwrap().rewrite(&kept)?;
+        }
+        Ok(deleted)
+    }
+}
+
+#[derive(Serialize)]
+struct TrajectoryRow<'a> {
+    trip_id: &'a str,
+    route_name: Option<&'a str>,
+    direction: Option<&'a str>,
+    vehicle_id: Option<&'a str>,
+    stop_id: &'a str,
+    stop_name: &'a str,
+    seq: u32,
+    #[serde(serialize_with = "timestamp::serialize_option")]
+    planned_time: Option<Timestamp>,
+    #[serde(serialize_with = "timestamp::serialize_option")]
+    actual_time: Option<Timestamp>,
+    status: PassageStatus,
+    delay_secs: Option<i64>,
+}
+
+struct Appender {
+    path: PathBuf,
+    file: File,
+    format: FileFormat,
+    /// CSV files get a header row before their first record.
+    needs_header: bool,
+}
+
+impl Appender {
+    fn open(path: PathBuf, format: FileFormat) -> io::Result<Appender> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let needs_header = file.metadata()?.len() == 0;
+
+        Ok(Appender {
+            path,
+            file,
+            format,
+            needs_header,
+        })
+    }
+
+    fn append<T: Serialize>(&mut self, record: &T) -> io::Result<()> {
+        let bytes = match self.format {
+            FileFormat::Jsonl => {
+                let mut line = serde_json::to_vec(record)?;
+                line.push(b'\n');
+                line
+            }
+            FileFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(self.needs_header)
+                    .from_writer(Vec::new());
+                writer.serialize(record)?;
+                writer.into_inner().map_err(|e| e.into_error())?
+            }
+        };
+
+        self.file.write_all(&bytes)?;
+        self.needs_header = false;
+        Ok(())
+    }
+
+    /// Every record in the file, skipping unreadable ones.
+    fn read<T: DeserializeOwned>(&self) -> io::Result<Vec<T>> {
+        let file = File::open(&self.path)?;
+        let records: Vec<Result<T, String>> = match self.format {
+            FileFormat::Jsonl => BufReader::new(file)
+                .lines()
+                .map(|line| {
+                    let line = line.map_err(|e| e.to_string())?;
+                    serde_json::from_str(&line).map_err(|e| e.to_string())
+                })
+                .collect(),
+            FileFormat::Csv => csv::Reader::from_reader(file)
+                .deserialize()
+                .map(|record| record.map_err(|e| e.to_string()))
+                .collect(),
+        };
+
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    println!("Skipping unreadable record in {:?}: {}", self.path, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Replaces the file with the given records at once.
+    fn rewrite<T: Serialize>(&mut self, records: &[T]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let _ = fs::remove_file(&tmp_path);
+
+        let mut tmp = Appender::open(tmp_path.clone(), self.format)?;
+        records.iter().try_for_each(|record| tmp.append(record))?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        *self = Appender::open(self.path.clone(), self.format)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::tests::{check_store, record};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn file_stores_keep_last_record_of_trip() {
+        for &format in &[FileFormat::Jsonl, FileFormat::Csv] {
+            let dir = TestDir::new();
+            let dir = &dir.path("store");
+
+            check_store(&FileStore::open(dir, format).unwrap());
+            let reopened = FileStore::open(dir, format).unwrap();
+            assert_eq!(reopened.edges().unwrap(), vec![Edge::new("A", "B")]);
+            // Compaction left only the latest record of each trip.
+            let samples = reopened.samples.lock().unwrap();
+            assert_eq!(samples.read::<SampleRecord>().unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn unchanged_samples_are_appended_once() {
+        let dir = TestDir::new();
+        let store = FileStore::open(&dir.path("store"), FileFormat::Jsonl).unwrap();
+
+        let appended = || {
+            store
+                .samples
+                .lock()
+                .unwrap()
+                .read::<SampleRecord>()
+                .unwrap()
+                .len()
+        };
+
+        store.save_sample(&record("1", 60, 30)).unwrap();
+        store.save_sample(&record("1", 60, 30)).unwrap();
+        assert_eq!(appended(), 1);
+        store.save_sample(&record("1", 60, 35)).unwrap();
+        assert_eq!(appended(), 2);
+
+        // A deleted sample saved again is written anew.
+        store.delete_sample(&record("1", 60, 35)).unwrap();
+        store.save_sample(&record("1", 60, 35)).unwrap();
+        assert_eq!(appended(), 1);
+    }
+}
@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+use crate::timestamp::{self, Timestamp};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Samples kept per fragment, oldest are dropped first.
+    pub max_samples: usize,
+    /// Samples older than this are dropped regardless of count.
+    pub retention_secs: u64,
+    /// Windows reported in fragment stats, e.g. `15m`, `1h` or `today`.
+    pub windows: Vec<Window>,
+    /// Weight of the newest sample in the moving average.
+    pub ewma_alpha: f64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            max_samples: 2000,
+            retention_secs: 24 * 3600,
+            windows: vec![Window::Last(15 * 60), Window::Last(3600), Window::Today],
+            ewma_alpha: 0.3,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Window {
+    /// Trailing number of seconds.
+    Last(u64),
+    /// Since the start of the current service day.
+    Today,
+}
+
+impl Window {
+    pub fn start(self, now: Timestamp) -> Timestamp {
+        match self {
+            Window::Last(secs) => now - chrono::Duration::seconds(secs as i64),
+            Window::Today => timestamp::service_day_start(&now),
+        }
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Window, String> {
+        if s == "today" {
+            return Ok(Window::Today);
+        }
+
+        parse_duration(&s)
+            .map(Window::Last)
+            .ok_or_else(|| format!("invalid window {:?}, expected e.g. 15m, 1h or today", s))
+    }
+}
+
+/// Parses positive durations such as `15m`, `1h` or `1d` into seconds; bare
+/// numbers are seconds.
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => duration.split_at(i),
+        None => (duration, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|&n| n > 0)
+        .and_then(|n| n.checked_mul(scale))
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Window::Today => write!(f, "today"),
+            Window::Last(secs) if secs % 3600 == 0 => write!(f, "{}h", secs / 3600),
+            Window::Last(secs) if secs % 60 == 0 => write!(f, "{}m", secs / 60),
+            Window::Last(secs) => write!(f, "{}s", secs),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct WindowStats {
+    pub window: String,
+    pub count: usize,
+    pub mean_secs: Option<f64>,
+    pub median_secs: Option<u64>,
+    pub p10_secs: Option<u64>,
+    pub p90_secs: Option<u64>,
+    pub min_secs: Option<u64>,
+    pub max_secs: Option<u64>,
+    pub ewma_secs: Option<f64>,
+}
+
+impl WindowStats {
+    /// Stats of durations given oldest first.
+    pub fn new(window: Window, durations: &[Duration], ewma_alpha: f64) -> WindowStats {
+        let secs: Vec<u64> = durations.iter().map(Duration::as_secs).collect();
+        let mut sorted = secs.clone();
+        sorted.sort_unstable();
+
+        let count = secs.len();
+        let mean_secs = if count > 0 {
+            Some(secs.iter().sum::<u64>() as f64 / count as f64)
+        } else {
+            None
+        };
+        let ewma_secs = secs.iter().fold(None, |avg: Option<f64>, &x| {
+            Some(match avg {
+                Some(avg) => ewma_alpha * x as f64 + (1.0 - ewma_alpha) * avg,
+                None => x as f64,
+            })
+        });
+
+        WindowStats {
+            window: window.to_string(),
+            count,
+            mean_secs,
+            median_secs: percentile(&sorted, 0.5),
+            p10_secs: percentile(&sorted, 0.1),
+            p90_secs: percentile(&sorted, 0.9),
+            min_secs: sorted.first().copied(),
+            max_secs: sorted.last().copied(),
+            ewma_secs,
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+pub(crate) fn percentile(sorted: &[u64], p: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+
+    Some(sorted[rank.max(1) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_parse_from_config() {
+        let config: HistoryConfig = toml::from_str(r#"windows = ["15m", "2h", "today"]"#).unwrap();
+        assert_eq!(
+            config.windows,
+            vec![Window::Last(900), Window::Last(7200), Window::Today]
+        );
+        assert_eq!(config.windows[0].to_string(), "15m");
+        assert!(toml::from_str::<HistoryConfig>(r#"windows = ["week"]"#).is_err());
+        assert!(toml::from_str::<HistoryConfig>(r#"windows = ["5µ"]"#).is_err());
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("15m"), Some(900));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("300"), Some(300));
+        assert_eq!(parse_duration("0h"), None);
+        assert_eq!(parse_duration("1w"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("1ż"), None);
+    }
+
+    #[test]
+    fn stats_summarize_window() {
+        let durations: Vec<_> = [60, 70, 80, 90, 400]
+            .iter()
+            .map(|&s| Duration::from_secs(s))
+            .collect();
+
+        let stats = WindowStats::new(Window::Last(900), &durations, 0.5);
+        assert_eq!(stats.count, 5);
+        assert_eq!(stats.mean_secs, Some(140.0));
+        assert_eq!(stats.median_secs, Some(80));
+        assert_eq!(stats.p10_secs, Some(60));
+        assert_eq!(stats.p90_secs, Some(400));
+        assert_eq!(stats.min_secs, Some(60));
+        assert_eq!(stats.max_secs, Some(400));
+        assert_eq!(stats.ewma_secs, Some(240.625));
+
+        let empty = WindowStats::new(Window::Today, &[], 0.5);
+        assert_eq!(empty.count, 0);
+        assert_eq!(empty.median_secs, None);
+    }
+}
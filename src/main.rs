@@ -1,8 +1,7 @@
 extern crate serde;
 extern crate serde_json;
 
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
@@ -11,19 +10,33 @@ use std::time::Duration;
 use actix::prelude::*;
 use actix_files::NamedFile;
 
-use chrono::Local;
-use futures::prelude::*;
-
+mod capture;
+mod clock;
+mod config;
+mod fake_ttss;
+mod file_store;
+mod history;
+mod network;
 mod passage;
+mod poll_plan;
+mod poller_health;
+mod replay;
+mod retention;
 mod route_fragment;
 mod route_fragment_registry;
+mod scheduler;
+mod snapshot;
+mod sqlite_store;
+mod stop_registry;
+mod storage;
+#[cfg(test)]
+mod test_dir;
+mod timestamp;
+mod trajectory;
 mod trip_registry;
+mod ttss;
 
-use actix_web::{
-    get, web, web::Data, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
-};
-
-use tokio::prelude::*;
+use actix_web::{web, web::Data, App, Error, HttpRequest, HttpResponse, HttpServer};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Welcome {
@@ -43,6 +56,21 @@ pub struct Welcome {
     stop_short_name: String,
 }
 
+impl Welcome {
+    fn service_window(&self) -> Option<poll_plan::ServiceWindow> {
+        use chrono::TimeZone;
+
+        if self.first_passage_time <= 0 || self.last_passage_time <= 0 {
+            return None;
+        }
+
+        Some(poll_plan::ServiceWindow {
+            first: timestamp::TIMEZONE.timestamp_millis(self.first_passage_time),
+            last: timestamp::TIMEZONE.timestamp_millis(self.last_passage_time),
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Actual {
     #[serde(rename = "actualRelativeTime")]
@@ -66,6 +94,13 @@ pub struct Actual {
     vehicle_id: Option<String>,
 }
 
+impl Actual {
+    /// Seconds since departure, for passages already departed.
+    fn age_secs(&self) -> u64 {
+        (-i64::from(self.actual_relative_time)).max(0) as u64
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Route {
     alerts: Vec<Option<serde_json::Value>>,
@@ -79,25 +114,54 @@ pub struct Route {
     short_name: String,
 }
 
-async fn fetch_stop_passage(s: &str) -> reqwest::Result<Welcome> {
-    println!("fetch");
-    let fs = format!("https://mpk.jacekk.net/proxy_tram.php/services/passageInfo/stopPassages/stopPoint?stopPoint={}&mode=departure", s);
-    let url = reqwest::Url::parse(&fs).unwrap();
+/// Poll interval after a fetch failed even with retries.
+const FAILED_FETCH_RETRY_SECS: u64 = 60;
 
-    reqwest::get(url).await?.json::<Welcome>().await
-}
+/// Predicted departures not seen as old within this time are forgotten.
+const STALE_PREDICTION_SECS: i64 = 120;
+
+const DEFAULT_SAMPLE_PAGE: usize = 100;
+const MAX_SAMPLE_PAGE: usize = 1000;
 
-#[derive(Debug)]
 struct StopState {
-    stop_id: &'static str,
+    stop_id: String,
+    /// Polls the stop and is handed to the trips seen there; without it
+    /// the stop only takes passages sent to it and never schedules a poll.
+    client: Option<Arc<dyn ttss::TtssClient>>,
+    clock: clock::SharedClock,
     name: Option<String>,
+    display_name: Option<String>,
     last_check: std::time::Instant,
     last_reparture_diff: Option<i32>,
-    prev_stop: Option<String>,
+    startup_delay: Duration,
+    next_priority: scheduler::Priority,
+    polling: poll_plan::PollingConfig,
+    /// Expected departure of each trip that has not left yet.
+    upcoming: HashMap<String, timestamp::Timestamp>,
+    service_window: Option<poll_plan::ServiceWindow>,
+    next_poll: Option<(SpawnHandle, timestamp::Timestamp)>,
+    measurement: config::Measurement,
 }
 
 impl Actor for StopState {
     type Context = Context<StopState>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.client.is_some() {
+            self.schedule_poll(self.startup_delay, ctx);
+        }
+    }
+}
+
+impl Supervised for StopState {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        println!("Restarting stop poller {}", self.stop_id);
+
+        poller_health::PollerHealth::from_registry().do_send(poller_health::PollerRestarted {
+            kind: poller_health::PollerKind::Stop,
+            id: self.stop_id.clone(),
+        });
+    }
 }
 
 #[derive(Message)]
@@ -112,119 +176,189 @@ impl Handler<UpdateRequest> for StopState {
     type Result = ();
 
     fn handle(&mut self, _msg: UpdateRequest, ctx: &mut Context<Self>) {
-        let x = actix::fut::wrap_future::<_, Self>(fetch_stop_passage(&self.stop_id));
-
-        ctx.wait(x.map(|_result, actor, _ctx| {
-            // println!("{:#?}", result);
-            actor.last_reparture_diff = _result
-                .as_ref()
-                .unwrap()
-                .old
-                .first()
-                .map(|x| x.actual_relative_time);
-
-            let actual = _result.as_ref().unwrap().actual.first();
-
-            let wait = actual.map(|x| x.actual_relative_time);
-
-            actor.last_reparture_diff = _result
-                .as_ref()
-                .unwrap()
-                .old
-                .first()
-                .map(|x| x.actual_relative_time);
-
-            let z = match wait {
-                Some(x) if x > 30 => x,
-                _ => 120,
-            };
+        let client = match &self.client {
+            Some(client) => client,
+            None => return,
+        };
+        let x = actix::fut::wrap_future::<_, Self>(
+            client.stop_passages(&self.stop_id, self.next_priority),
+        );
+
+        ctx.wait(x.map(|_result, actor, ctx| {
+            poller_health::catch_panic(actor, ctx, |actor, _ctx| {
+                // println!("{:#?}", result);
+                poller_health::PollerHealth::from_registry().do_send(
+                    poller_health::FetchOutcome::new(
+                        poller_health::PollerKind::Stop,
+                        &actor.stop_id,
+                        actor.clock.now(),
+                        &_result,
+                    ),
+                );
 
-            let update_reason = if let Some(act) = actual {
-                Some(String::from(format!(
-                    "{} {} {}",
-                    act.pattern_text, act.direction, act.trip_id
-                )))
-            } else {
-                None
-            };
+                let passage = match _result {
+                    Ok(passage) => passage,
+                    Err(e) => {
+                        println!(
+                            "{:?} fetch failed: {}, update in {}",
+                            actor.name.as_ref().unwrap_or(&actor.stop_id),
+                            e,
+                            FAILED_FETCH_RETRY_SECS
+                        );
+                        actor.next_priority = scheduler::Priority::Normal;
+                        actor.schedule_poll(Duration::from_secs(FAILED_FETCH_RETRY_SECS), _ctx);
+                        return;
+                    }
+                };
+
+                actor.sync_passage(passage, _ctx);
+
+                let plan = actor.plan_poll();
+                println!(
+                    "{:?} update in {} due to {:?}",
+                    actor.name.as_ref().unwrap_or(&actor.stop_id.to_string()),
+                    plan.delay.as_secs(),
+                    plan.reason,
+                );
+                actor.next_priority = plan.priority;
+                actor.schedule_poll(plan.delay, _ctx);
+            })
+        }));
+    }
+}
+
+/// Feeds an already fetched stop passage into the actor, as done in replay.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct StopPassageSync {
+    passage: Welcome,
+}
+
+impl Handler<StopPassageSync> for StopState {
+    type Result = ();
+
+    fn handle(&mut self, msg: StopPassageSync, ctx: &mut Context<Self>) {
+        poller_health::catch_panic(self, ctx, |actor, ctx| actor.sync_passage(msg.passage, ctx));
+    }
+}
+
+/// Departure of a trip at this stop, as predicted by the trip itself.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct PredictedDeparture {
+    trip_id: String,
+    time: timestamp::Timestamp,
+}
+
+impl Handler<PredictedDeparture> for StopState {
+    type Result = ();
+
+    fn handle(&mut self, msg: PredictedDeparture, ctx: &mut Context<Self>) {
+        poller_health::catch_panic(self, ctx, |actor, ctx| actor.predicted_departure(msg, ctx));
+    }
+}
+
+impl StopState {
+    fn predicted_departure(&mut self, msg: PredictedDeparture, ctx: &mut Context<Self>) {
+        self.upcoming.insert(msg.trip_id, msg.time);
+        if self.client.is_none() {
+            return;
+        }
 
+        let plan = self.plan_poll();
+        let poll_at = self.clock.now() + chrono::Duration::from_std(plan.delay).unwrap();
+        if !matches!(self.next_poll, Some((_, at)) if at <= poll_at) {
             println!(
-                "{:?} update in {} due to {:?}",
-                actor.name.as_ref().unwrap_or(&actor.stop_id.to_string()),
-                z as u64,
-                update_reason,
+                "{:?} update moved to {} due to {:?}",
+                self.name.as_ref().unwrap_or(&self.stop_id),
+                poll_at,
+                plan.reason,
             );
-            _ctx.notify_later(UpdateRequest {}, Duration::from_secs(z as u64));
+            self.next_priority = plan.priority;
+            self.schedule_poll(plan.delay, ctx);
+        }
+    }
 
-            actor.name = Some(String::from(&_result.as_ref().unwrap().stop_name));
+    fn schedule_poll(&mut self, delay: Duration, ctx: &mut Context<Self>) {
+        if let Some((handle, _)) = self.next_poll.take() {
+            ctx.cancel_future(handle);
+        }
 
-            let l = route_fragment_registry::RouteFragmentRegistry::from_registry()
-                .send(route_fragment_registry::GetRouteFragment::new(
-                    String::from(&_result.as_ref().unwrap().stop_short_name),
-                ))
-                .into_actor(actor);
+        let handle = ctx.notify_later(UpdateRequest {}, delay);
+        let poll_at = self.clock.now() + chrono::Duration::from_std(delay).unwrap();
+        self.next_poll = Some((handle, poll_at));
+    }
 
-            let stop_name = std::rc::Rc::new(String::from(&_result.as_ref().unwrap().stop_name));
-            let s1 = stop_name.clone();
+    fn plan_poll(&mut self) -> poll_plan::PollPlan {
+        let now = self.clock.now();
+        let stale = now - chrono::Duration::seconds(STALE_PREDICTION_SECS);
+        self.upcoming.retain(|_, time| *time > stale);
 
-            let olde = std::rc::Rc::new(_result.unwrap().old);
-            let xd = olde.clone();
-            let xd2 = olde.clone();
+        let upcoming: Vec<_> = self.upcoming.values().copied().collect();
+        poll_plan::plan(&self.polling, now, &upcoming, self.service_window.as_ref())
+    }
 
-            _ctx.wait(l.map(move |_rresult, _actor, _cctx| {
-                let fragment = _rresult.unwrap();
+    fn sync_passage(&mut self, passage: Welcome, _ctx: &mut Context<Self>) {
+        let now = self.clock.now();
 
-                fragment.do_send(
-                    route_fragment_registry::route_fragment::RouteFragment::update_start(&*s1),
+        for x in &passage.old {
+            self.upcoming.remove(&x.trip_id);
+        }
+        for x in &passage.actual {
+            let time = now + chrono::Duration::seconds(x.actual_relative_time.into());
+            self.upcoming.insert(x.trip_id.clone(), time);
+        }
+        self.service_window = passage.service_window();
+
+        if self.measurement.uses_trips() {
+            for x in passage.actual.iter().chain(passage.old.iter()) {
+                trip_registry::TripRegistry::from_registry().do_send(
+                    trip_registry::RegisterTrip::new(
+                        x.trip_id.clone(),
+                        self.client.clone(),
+                        self.clock.clone(),
+                    )
+                    .with_vehicle(x.vehicle_id.clone()),
                 );
+            }
+        }
 
-                xd.iter().for_each(|x| {
-                    let lol = x.actual_relative_time.into();
-                    let drift = chrono::Duration::seconds(lol);
-                    let ttime = Local::now().time() + drift;
-
-                    fragment.do_send(
-                        route_fragment_registry::route_fragment::FragmentEntryEvent {
-                            trip_id: String::from(&x.trip_id),
-                            instant: std::time::Instant::now(),
-                            time: ttime,
-                        },
-                    );
-                });
-            }));
-
-            if let Some(stop) = &actor.prev_stop {
-                let x = route_fragment_registry::RouteFragmentRegistry::from_registry()
-                    .send(route_fragment_registry::GetRouteFragment::new(
-                        String::from(stop),
-                    ))
-                    .into_actor(actor)
-                    .map(move |rr, _, _| {
-                        let fragment = rr.unwrap();
-
-                        fragment.do_send(
-                            route_fragment_registry::route_fragment::RouteFragment::update_stop(
-                                &*stop_name.clone(),
-                            ),
-                        );
+        self.last_reparture_diff = passage.old.first().map(|x| x.actual_relative_time);
 
-                        xd2.iter().for_each(|x| {
-                            let ttime = Local::now().time()
-                                + chrono::Duration::seconds(x.actual_relative_time.into());
-
-                            fragment.do_send(
-                                route_fragment_registry::route_fragment::FragmentLeaveEvent {
-                                    trip_id: String::from(&x.trip_id),
-                                    instant: std::time::Instant::now(),
-                                    time: ttime,
-                                },
-                            );
-                        });
-                    });
-
-                _ctx.wait(x);
-            }
-        }));
+        self.name = Some(String::from(&passage.stop_name));
+        let stop_name = self
+            .display_name
+            .clone()
+            .unwrap_or_else(|| String::from(&passage.stop_name));
+
+        let registry = route_fragment_registry::RouteFragmentRegistry::from_registry();
+        registry.do_send(route_fragment_registry::StopName {
+            id: self.stop_id.clone(),
+            name: stop_name,
+        });
+
+        if !self.measurement.uses_stops() {
+            return;
+        }
+        for x in &passage.old {
+            let time = now + chrono::Duration::seconds(x.actual_relative_time.into());
+
+            registry.do_send(route_fragment_registry::Departure {
+                stop_id: self.stop_id.clone(),
+                stop_name: None,
+                next_stop_id: None,
+                trip: timestamp::TripKey::new(&x.trip_id, &time),
+                source: route_fragment::Source::Stop,
+                passage: x.passageid.clone(),
+                age_secs: x.age_secs(),
+                line: Some(route_fragment::Line {
+                    route: x.pattern_text.clone(),
+                    direction: x.direction.clone(),
+                }),
+                vehicle_id: x.vehicle_id.clone(),
+                time,
+            });
+        }
     }
 }
 
@@ -240,68 +374,580 @@ impl Handler<PrintState> for StopState {
     }
 }
 
-const STOP_IDS: [&str; 38] = [
-    // Mogilskie → Czyżyny
-    "12529", "12919", "13019", "304019", "281119", "11319", "11219", "40719",
-    // Czyżyny → Mogilskie
-    "40829", "40729", "11229", "11329", "281129", "304029", "13029", "12929",
-    // Grzegórzeckie → Huta
-    "36519", "285919", "36719", "36819", "36919", "37019", "303319", "287119", "93019", "304119",
-    "40919", //
-    // Huta → Grzegórzeckie
-    "40849", "40929", "304129", "93029", "287129", "303329", "37029", "36929", "36829", "36729",
-    "285929", //
-];
+async fn collect_stats(
+    registry: &Addr<route_fragment_registry::RouteFragmentRegistry>,
+    config: &config::Config,
+    filter: &route_fragment::LineFilter,
+) -> Result<Vec<route_fragment::RouteFragmentStats>, MailboxError> {
+    let order: HashMap<_, _> = config
+        .stop_ids()
+        .enumerate()
+        .map(|(i, id)| (id, i))
+        .collect();
+
+    // Only fragments starting at a corridor stop, in corridor order.
+    let mut fragments: Vec<_> = registry
+        .send(route_fragment_registry::ListRouteFragments)
+        .await?
+        .into_iter()
+        .filter_map(|(edge, fragment)| {
+            let from = *order.get(edge.from.as_str())?;
+            let to = order.get(edge.to.as_str()).copied().unwrap_or(usize::MAX);
+            Some(((from, to, edge), fragment))
+        })
+        .collect();
+    fragments.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut vec = Vec::new();
+    for (_, fragment) in fragments {
+        vec.push(
+            fragment
+                .send(route_fragment::FilteredStatusRequest(filter.clone()))
+                .await?,
+        );
+    }
+
+    Ok(vec)
+}
 
 async fn handle_frag_stat(
-    _: HttpRequest,
+    filter: web::Query<route_fragment::LineFilter>,
     state: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+    config: Data<config::Config>,
 ) -> Result<HttpResponse, Error> {
-    let mut vec = Vec::new();
-    for stop in STOP_IDS.iter() {
-        let resp = state
-            .send(route_fragment_registry::GetRouteFragment::new(
-                stop.to_string(),
-            ))
-            .and_then(|x| x.send(route_fragment_registry::route_fragment::FragmentStatusRequest))
-            .await
-            .unwrap();
-        vec.push(resp);
+    match collect_stats(&state, &config, &filter).await {
+        Ok(vec) => Ok(HttpResponse::Ok().json(vec)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+async fn find_fragment(
+    registry: &Addr<route_fragment_registry::RouteFragmentRegistry>,
+    id: &str,
+) -> Result<Option<Addr<route_fragment::RouteFragment>>, MailboxError> {
+    match id.parse() {
+        Ok(edge) => {
+            registry
+                .send(route_fragment_registry::FindRouteFragment { edge })
+                .await
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+async fn handle_fragment(
+    id: web::Path<String>,
+    filter: web::Query<route_fragment::LineFilter>,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let stats = match find_fragment(&registry, &id).await {
+        Ok(Some(fragment)) => {
+            fragment
+                .send(route_fragment::FilteredStatusRequest(filter.into_inner()))
+                .await
+        }
+        Ok(None) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Err(e),
+    };
+
+    match stats {
+        Ok(stats) => Ok(HttpResponse::Ok().json(stats)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[derive(Deserialize)]
+struct SamplesQuery {
+    from: Option<chrono::DateTime<chrono::FixedOffset>>,
+    to: Option<chrono::DateTime<chrono::FixedOffset>>,
+    /// The `next` cursor of the previous page.
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn handle_fragment_samples(
+    id: web::Path<String>,
+    query: web::Query<SamplesQuery>,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let after = match query.after.as_deref().map(str::parse).transpose() {
+        Ok(after) => after,
+        Err(_) => return Ok(HttpResponse::BadRequest().finish()),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_SAMPLE_PAGE);
+    if limit == 0 {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+
+    let request = route_fragment::SamplesRequest {
+        from: query.from.map(|t| t.with_timezone(&timestamp::TIMEZONE)),
+        to: query.to.map(|t| t.with_timezone(&timestamp::TIMEZONE)),
+        after,
+        limit: limit.min(MAX_SAMPLE_PAGE),
+    };
+    let page = match find_fragment(&registry, &id).await {
+        Ok(Some(fragment)) => fragment.send(request).await,
+        Ok(None) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Err(e),
+    };
+
+    match page {
+        Ok(page) => Ok(HttpResponse::Ok().json(page)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
     }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    from: Option<chrono::DateTime<chrono::FixedOffset>>,
+    to: Option<chrono::DateTime<chrono::FixedOffset>>,
+    bucket: Option<String>,
+    #[serde(flatten)]
+    filter: route_fragment::LineFilter,
+}
+
+impl HistoryQuery {
+    /// Time range and bucket size asked for, defaulting to the last day in
+    /// stored buckets. Coarser buckets must be a multiple of those.
+    fn range(&self, stored_secs: u64) -> Option<(timestamp::Timestamp, timestamp::Timestamp, u64)> {
+        let bucket_secs = match &self.bucket {
+            Some(bucket) => history::parse_duration(bucket)?,
+            None => stored_secs,
+        };
+        if bucket_secs % stored_secs != 0 {
+            return None;
+        }
+
+        let to = self
+            .to
+            .map(|t| t.with_timezone(&timestamp::TIMEZONE))
+            .unwrap_or_else(|| clock::system().now());
+        let from = self
+            .from
+            .map(|t| t.with_timezone(&timestamp::TIMEZONE))
+            .unwrap_or_else(|| to - chrono::Duration::days(1));
+
+        Some((from, to, bucket_secs))
+    }
+}
+
+async fn handle_fragment_history(
+    id: web::Path<String>,
+    query: web::Query<HistoryQuery>,
+    store: Data<Option<storage::SharedStore>>,
+    config: Data<config::Config>,
+) -> Result<HttpResponse, Error> {
+    let (store, edge) = match (store.get_ref(), id.parse()) {
+        (Some(store), Ok(edge)) => (store.clone(), edge),
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let query = query.into_inner();
+    let stored_secs = config.storage.retention.bucket_secs;
+    let (from, to, bucket_secs) = match query.range(stored_secs) {
+        Some(range) => range,
+        None => return Ok(HttpResponse::BadRequest().finish()),
+    };
+
+    let buckets = web::block(move || {
+        retention::history(&*store, &edge, from, to, &query.filter, stored_secs)
+            .map(|buckets| retention::resample(buckets, bucket_secs))
+    })
+    .await;
+
+    match buckets {
+        Ok(buckets) => Ok(HttpResponse::Ok().json(buckets)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[derive(Serialize)]
+struct FragmentHistory {
+    from: String,
+    to: String,
+    buckets: Vec<retention::Aggregate>,
+}
+
+async fn handle_corridor_history(
+    name: web::Path<String>,
+    query: web::Query<HistoryQuery>,
+    store: Data<Option<storage::SharedStore>>,
+    config: Data<config::Config>,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let store = match store.get_ref() {
+        Some(store) => store.clone(),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let network = match registry.send(route_fragment_registry::GetNetwork).await {
+        Ok(network) => network,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+    let edges: Vec<_> = match network.corridors().iter().find(|c| c.name == *name) {
+        Some(corridor) => corridor
+            .stops
+            .windows(2)
+            .map(|pair| route_fragment::Edge::new(&pair[0], &pair[1]))
+            .collect(),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let query = query.into_inner();
+    let stored_secs = config.storage.retention.bucket_secs;
+    let (from, to, bucket_secs) = match query.range(stored_secs) {
+        Some(range) => range,
+        None => return Ok(HttpResponse::BadRequest().finish()),
+    };
+
+    let fragments = web::block(move || {
+        edges
+            .into_iter()
+            .map(|edge| {
+                let buckets =
+                    retention::history(&*store, &edge, from, to, &query.filter, stored_secs)?;
+                Ok(FragmentHistory {
+                    from: edge.from,
+                    to: edge.to,
+                    buckets: retention::resample(buckets, bucket_secs),
+                })
+            })
+            .collect::<std::io::Result<Vec<_>>>()
+    })
+    .await;
+
+    match fragments {
+        Ok(fragments) => Ok(HttpResponse::Ok().json(fragments)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+async fn handle_fragment_lines(
+    id: web::Path<String>,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let lines = match find_fragment(&registry, &id).await {
+        Ok(Some(fragment)) => fragment.send(route_fragment::LineStatsRequest).await,
+        Ok(None) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Err(e),
+    };
+
+    match lines {
+        Ok(lines) => Ok(HttpResponse::Ok().json(lines)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    healthy: bool,
+    pollers: Vec<poller_health::PollerStatus>,
+}
+
+async fn handle_health(
+    _: HttpRequest,
+    health: Data<Addr<poller_health::PollerHealth>>,
+) -> Result<HttpResponse, Error> {
+    let pollers = match health.send(poller_health::GetPollerStatus).await {
+        Ok(pollers) => pollers,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+    let healthy = !pollers.iter().any(|p| p.failing);
+
+    let report = HealthReport { healthy, pollers };
+    if healthy {
+        Ok(HttpResponse::Ok().json(report))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(report))
+    }
+}
+
+async fn handle_scheduler(
+    _: HttpRequest,
+    scheduler: Data<Addr<scheduler::RequestScheduler>>,
+) -> Result<HttpResponse, Error> {
+    match scheduler.send(scheduler::GetSchedulerStats).await {
+        Ok(stats) => Ok(HttpResponse::Ok().json(stats)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+async fn handle_trips(
+    _: HttpRequest,
+    trips: Data<Addr<trip_registry::TripRegistry>>,
+) -> Result<HttpResponse, Error> {
+    match trips.send(trip_registry::ListTrips).await.and_then(|r| r) {
+        Ok(summaries) => Ok(HttpResponse::Ok().json(summaries)),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+/// Covers live trips only, retired ones are answered with 404.
+async fn handle_trip(
+    id: web::Path<String>,
+    trips: Data<Addr<trip_registry::TripRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let trajectory = trips
+        .send(trip_registry::GetTrajectory {
+            id: id.into_inner(),
+        })
+        .await
+        .and_then(|r| r);
+
+    match trajectory {
+        Ok(Some(trajectory)) => Ok(HttpResponse::Ok().json(trajectory)),
+        Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+async fn handle_network_json(
+    _: HttpRequest,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let network = match registry.send(route_fragment_registry::GetNetwork).await {
+        Ok(network) => network,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    Ok(HttpResponse::Ok().json(network.export()))
+}
+
+async fn handle_network_graphml(
+    _: HttpRequest,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let network = match registry.send(route_fragment_registry::GetNetwork).await {
+        Ok(network) => network,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/graphml+xml")
+        .body(network.to_graphml()))
+}
+
+#[derive(Serialize)]
+struct StopNeighbours<'a> {
+    #[serde(flatten)]
+    stop: &'a network::StopNode,
+    successors: Vec<&'a str>,
+    predecessors: Vec<&'a str>,
+}
+
+async fn handle_network_stop(
+    id: web::Path<String>,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let network = match registry.send(route_fragment_registry::GetNetwork).await {
+        Ok(network) => network,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    match network.stop(&id) {
+        Some(stop) => Ok(HttpResponse::Ok().json(StopNeighbours {
+            stop,
+            successors: network.successors(&id),
+            predecessors: network.predecessors(&id),
+        })),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[derive(Deserialize)]
+struct PathQuery {
+    from: String,
+    to: String,
+}
+
+async fn handle_network_path(
+    query: web::Query<PathQuery>,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let travel_time = registry
+        .send(route_fragment_registry::GetTravelTime {
+            from: query.from,
+            to: query.to,
+        })
+        .await
+        .and_then(|r| r);
+
+    match travel_time {
+        Ok(Some(travel_time)) => Ok(HttpResponse::Ok().json(travel_time)),
+        Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
 
-    Ok(HttpResponse::Ok().json(vec))
+#[derive(Serialize)]
+struct CorridorReport<'a> {
+    name: &'a str,
+    stops: &'a [String],
+    edges: Vec<&'a network::EdgeInfo>,
+}
+
+async fn handle_network_corridors(
+    _: HttpRequest,
+    registry: Data<Addr<route_fragment_registry::RouteFragmentRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let network = match registry.send(route_fragment_registry::GetNetwork).await {
+        Ok(network) => network,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    let corridors: Vec<_> = network
+        .corridors()
+        .iter()
+        .map(|corridor| CorridorReport {
+            name: &corridor.name,
+            stops: &corridor.stops,
+            edges: network.corridor_edges(corridor),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(corridors))
 }
 
 async fn file(_: HttpRequest) -> actix_web::Result<NamedFile> {
     Ok(NamedFile::open("vis.html")?)
 }
 
+fn spawn_stop_pollers(
+    config: &config::Config,
+    client: Option<Arc<dyn ttss::TtssClient>>,
+    clock: clock::SharedClock,
+) -> Vec<(String, Addr<StopState>)> {
+    let stagger = Duration::from_millis(config.ttss.scheduler.startup_stagger_ms);
+    let mut stops = Vec::new();
+
+    for corridor in &config.corridors {
+        println!("Monitoring corridor {}", corridor.name);
+
+        for stop in &corridor.stops {
+            let state = StopState {
+                stop_id: stop.id.clone(),
+                client: client.clone(),
+                clock: clock.clone(),
+                name: stop.name.clone(),
+                display_name: stop.name.clone(),
+                last_check: clock.instant(),
+                last_reparture_diff: None,
+                startup_delay: stagger * stops.len() as u32,
+                next_priority: scheduler::Priority::Normal,
+                polling: config.polling.clone(),
+                upcoming: HashMap::new(),
+                service_window: None,
+                next_poll: None,
+                measurement: config.measurement,
+            };
+            let actor_addr = Supervisor::start(move |_ctx| state);
+            stop_registry::StopRegistry::from_registry().do_send(stop_registry::RegisterStop {
+                id: stop.id.clone(),
+                addr: actor_addr.clone(),
+            });
+            stops.push((stop.id.clone(), actor_addr));
+        }
+    }
+
+    stops
+}
+
+async fn run_fake_ttss(scenario_path: &str, bind: &str) -> std::io::Result<()> {
+    let fake = fake_ttss::FakeTtss::new(fake_ttss::Scenario::load(scenario_path)?);
+    println!("Serving fake TTSS scenario {} on {}", scenario_path, bind);
+
+    HttpServer::new(move || {
+        App::new()
+            .data(fake.clone())
+            .configure(fake_ttss::configure)
+    })
+    .bind(bind)?
+    .run()
+    .await
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    for x in 0..STOP_IDS.len() - 1 {
-        let actor_addr = StopState::create(|_ctx| StopState {
-            stop_id: STOP_IDS[x],
-            name: None,
-            last_check: std::time::Instant::now(),
-            last_reparture_diff: None,
-            prev_stop: if x == 0 {
-                None
-            } else {
-                Some(String::from(STOP_IDS[x - 1]))
-            },
-        });
-        actor_addr.do_send(UpdateRequest {});
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("fake-ttss") {
+        let scenario = args.get(2).map(String::as_str).unwrap_or("scenario.toml");
+        let bind = args.get(3).map(String::as_str).unwrap_or("127.0.0.1:8081");
+
+        return run_fake_ttss(scenario, bind).await;
+    }
+    if args.get(1).map(String::as_str) == Some("replay") {
+        let capture = args
+            .get(2)
+            .map(String::as_str)
+            .unwrap_or("capture.jsonl.gz");
+        let history = args.get(3).map(String::as_str).unwrap_or("history.jsonl");
+
+        return replay::run(&config::Config::from_env()?, capture, history).await;
     }
 
-    let rfr = route_fragment_registry::RouteFragmentRegistry::from_registry();
+    let config = config::Config::from_env()?;
+    let mut reqwest_client =
+        ttss::ReqwestClient::new(&config.ttss).map_err(std::io::Error::other)?;
+    if let Some(path) = &config.ttss.capture_path {
+        reqwest_client = reqwest_client.with_recorder(capture::Recorder::open(path)?);
+    }
+    let store = config.storage.open()?;
+    let trips = trip_registry::TripRegistry::new(config.trips.retirement(store.clone())?).start();
+    actix::Registry::set(trips.clone());
+
+    let scheduler = scheduler::RequestScheduler::new(config.ttss.scheduler.clone()).start();
+    let client: Arc<dyn ttss::TtssClient> =
+        Arc::new(reqwest_client.with_scheduler(scheduler.clone()));
+
+    let _compactor = store.clone().map(|store| {
+        retention::Compactor::new(store, config.storage.retention.clone(), clock::system()).start()
+    });
+
+    let rfr = route_fragment_registry::RouteFragmentRegistry::new(clock::system())
+        .with_network(network::Network::from_config(&config))
+        .with_history(config.history.clone())
+        .with_store(store.clone())
+        .with_snapshot(config.snapshot.clone())
+        .start();
+    actix::Registry::set(rfr.clone());
+    let registry = rfr.clone();
+
+    // Supervised pollers are only restarted while their address is held.
+    let _stops = spawn_stop_pollers(&config, Some(client), clock::system());
+    let health = poller_health::PollerHealth::from_registry();
 
     HttpServer::new(move || {
         App::new()
             .data(rfr.clone())
+            .data(health.clone())
+            .data(scheduler.clone())
+            .data(trips.clone())
+            .data(config.clone())
+            .data(store.clone())
             .route("/", web::get().to(file))
             .service(web::resource("/stats.json").to(handle_frag_stat))
+            .service(web::resource("/health").to(handle_health))
+            .service(web::resource("/scheduler.json").to(handle_scheduler))
+            .service(web::resource("/trips").to(handle_trips))
+            .service(web::resource("/trips/{id}").to(handle_trip))
+            .service(web::resource("/fragments/{id}").to(handle_fragment))
+            .service(web::resource("/fragments/{id}/lines").to(handle_fragment_lines))
+            .service(web::resource("/fragments/{id}/samples").to(handle_fragment_samples))
+            .service(web::resource("/network.json").to(handle_network_json))
+            .service(web::resource("/network.graphml").to(handle_network_graphml))
+            .service(web::resource("/network/stops/{id}").to(handle_network_stop))
+            .service(web::resource("/network/path").to(handle_network_path))
+            .service(web::resource("/network/corridors").to(handle_network_corridors))
+            .service(web::resource("/api/fragments/{id}/history").to(handle_fragment_history))
+            .service(web::resource("/api/corridors/{name}/history").to(handle_corridor_history))
     })
     .bind("127.0.0.1:8080")?
     .run()
-    .await
+    .await?;
+
+    // The server stops gracefully on SIGTERM and SIGINT, while actors keep
+    // running long enough to save trips in flight.
+    println!("Saving snapshot before exit");
+    registry
+        .send(route_fragment_registry::SaveSnapshot)
+        .await
+        .map_err(std::io::Error::other)?
 }
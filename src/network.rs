@@ -0,0 +1,274 @@
+use serde::Serialize;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+
+use crate::config::Config;
+use crate::route_fragment::Edge;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StopNode {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EdgeInfo {
+    pub from: String,
+    pub to: String,
+    /// Consecutive stops of a configured corridor.
+    pub configured: bool,
+    /// Trips seen departing both stops one after another.
+    pub observed_trips: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CorridorPath {
+    pub name: String,
+    pub stops: Vec<String>,
+}
+
+/// Directed graph of stop points, joined by the fragments trams travel.
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    stops: BTreeMap<String, StopNode>,
+    edges: BTreeMap<Edge, EdgeInfo>,
+    corridors: Vec<CorridorPath>,
+}
+
+#[derive(Serialize)]
+pub struct NetworkExport<'a> {
+    pub stops: Vec<&'a StopNode>,
+    pub edges: Vec<&'a EdgeInfo>,
+}
+
+impl Network {
+    pub fn from_config(config: &Config) -> Network {
+        let mut network = Network::default();
+
+        for corridor in &config.corridors {
+            for stop in &corridor.stops {
+                network.add_stop(&stop.id);
+                if let Some(name) = &stop.name {
+                    network.name_stop(&stop.id, name);
+                }
+            }
+            for pair in corridor.stops.windows(2) {
+                network
+                    .add_edge(Edge::new(&pair[0].id, &pair[1].id))
+                    .configured = true;
+            }
+            network.corridors.push(CorridorPath {
+                name: corridor.name.clone(),
+                stops: corridor.stops.iter().map(|s| s.id.clone()).collect(),
+            });
+        }
+
+        network
+    }
+
+    pub fn add_stop(&mut self, id: &str) -> &mut StopNode {
+        self.stops
+            .entry(String::from(id))
+            .or_insert_with(|| StopNode {
+                id: String::from(id),
+                name: None,
+            })
+    }
+
+    pub fn name_stop(&mut self, id: &str, name: &str) {
+        self.add_stop(id).name = Some(String::from(name));
+    }
+
+    pub fn stop(&self, id: &str) -> Option<&StopNode> {
+        self.stops.get(id)
+    }
+
+    pub fn stop_name(&self, id: &str) -> Option<&str> {
+        self.stops.get(id).and_then(|s| s.name.as_deref())
+    }
+
+    pub fn add_edge(&mut self, edge: Edge) -> &mut EdgeInfo {
+        self.add_stop(&edge.from);
+        self.add_stop(&edge.to);
+
+        let (from, to) = (edge.from.clone(), edge.to.clone());
+        self.edges.entry(edge).or_insert(EdgeInfo {
+            from,
+            to,
+            configured: false,
+            observed_trips: 0,
+        })
+    }
+
+    pub fn observe_trip(&mut self, edge: Edge) {
+        self.add_edge(edge).observed_trips += 1;
+    }
+
+    /// Takes back a trip counted by `observe_trip`. Returns whether that
+    /// removed the edge, as no other trip was seen on it.
+    pub fn unobserve_trip(&mut self, edge: &Edge) -> bool {
+        let info = match self.edges.get_mut(edge) {
+            Some(info) => info,
+            None => return false,
+        };
+        info.observed_trips = info.observed_trips.saturating_sub(1);
+        if info.configured || info.observed_trips > 0 {
+            return false;
+        }
+
+        self.edges.remove(edge);
+        true
+    }
+
+    pub fn successors(&self, id: &str) -> Vec<&str> {
+        self.edges
+            .keys()
+            .filter(|e| e.from == id)
+            .map(|e| e.to.as_str())
+            .collect()
+    }
+
+    pub fn predecessors(&self, id: &str) -> Vec<&str> {
+        self.edges
+            .keys()
+            .filter(|e| e.to == id)
+            .map(|e| e.from.as_str())
+            .collect()
+    }
+
+    /// Stops along the path with fewest fragments from `from` to `to`.
+    pub fn path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        if !self.stops.contains_key(from) || !self.stops.contains_key(to) {
+            return None;
+        }
+
+        let mut came_from: HashMap<&str, &str> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(from);
+        while let Some(stop) = queue.pop_front() {
+            if stop == to {
+                let mut path = vec![String::from(to)];
+                let mut current = to;
+                while current != from {
+                    current = came_from[current];
+                    path.push(String::from(current));
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for next in self.successors(stop) {
+                if next != from && !came_from.contains_key(next) {
+                    came_from.insert(next, stop);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn corridors(&self) -> &[CorridorPath] {
+        &self.corridors
+    }
+
+    pub fn corridor_edges(&self, corridor: &CorridorPath) -> Vec<&EdgeInfo> {
+        corridor
+            .stops
+            .windows(2)
+            .filter_map(|pair| self.edges.get(&Edge::new(&pair[0], &pair[1])))
+            .collect()
+    }
+
+    pub fn export(&self) -> NetworkExport<'_> {
+        NetworkExport {
+            stops: self.stops.values().collect(),
+            edges: self.edges.values().collect(),
+        }
+    }
+
+    pub fn to_graphml(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+        out.push_str("  <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n");
+        out.push_str(
+            "  <key id=\"configured\" for=\"edge\" attr.name=\"configured\" attr.type=\"boolean\"/>\n",
+        );
+        out.push_str(
+            "  <key id=\"observed_trips\" for=\"edge\" attr.name=\"observed_trips\" attr.type=\"long\"/>\n",
+        );
+        out.push_str("  <graph id=\"mpkflow\" edgedefault=\"directed\">\n");
+
+        for stop in self.stops.values() {
+            let _ = write!(out, "    <node id=\"{}\"", xml_escape(&stop.id));
+            match &stop.name {
+                Some(name) => {
+                    let _ = writeln!(
+                        out,
+                        "><data key=\"name\">{}</data></node>",
+                        xml_escape(name)
+                    );
+                }
+                None => out.push_str("/>\n"),
+            }
+        }
+        for edge in self.edges.values() {
+            let _ = writeln!(
+                out,
+                "    <edge source=\"{}\" target=\"{}\"><data key=\"configured\">{}</data><data key=\"observed_trips\">{}</data></edge>",
+                xml_escape(&edge.from),
+                xml_escape(&edge.to),
+                edge.configured,
+                edge.observed_trips
+            );
+        }
+
+        out.push_str("  </graph>\n</graphml>\n");
+        out
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_follows_observed_branch() {
+        let config: Config = toml::from_str(
+            r#"
+            [[corridor]]
+            name = "main"
+            stops = [{ id = "A", name = "Rondo & Co" }, { id = "B" }, { id = "C" }]
+            "#,
+        )
+        .unwrap();
+        let mut network = Network::from_config(&config);
+        network.observe_trip(Edge::new("B", "D"));
+        network.observe_trip(Edge::new("D", "E"));
+
+        assert_eq!(network.successors("B"), vec!["C", "D"]);
+        assert_eq!(network.predecessors("D"), vec!["B"]);
+        assert_eq!(
+            network.path("A", "E"),
+            Some(vec![
+                String::from("A"),
+                String::from("B"),
+                String::from("D"),
+                String::from("E")
+            ])
+        );
+        assert_eq!(network.path("E", "A"), None);
+
+        let graphml = network.to_graphml();
+        assert!(graphml.contains("<node id=\"A\"><data key=\"name\">Rondo &amp; Co</data></node>"));
+        assert!(graphml.contains("<edge source=\"B\" target=\"D\">"));
+    }
+}
@@ -2,14 +2,24 @@ extern crate serde;
 extern crate serde_json;
 
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use std::time::Duration;
 
 use actix::prelude::*;
-use chrono::Local;
 use chrono::NaiveTime;
 
-#[path = "route_fragment_registry.rs"]
-mod route_fragment_registry;
+use crate::clock::SharedClock;
+use crate::poller_health::{
+    self, FetchOutcome, PollerHealth, PollerKind, PollerRestarted, PollerRetired,
+};
+use crate::route_fragment;
+use crate::route_fragment_registry;
+use crate::scheduler::Priority;
+use crate::stop_registry::{StopRegistry, TripPredictions};
+use crate::timestamp::{self, TripKey};
+use crate::trajectory::{LogSink, Trajectory, TrajectorySink, TrajectoryStop, TripSummary};
+use crate::trip_registry::{TripRegistry, TripRetired};
+use crate::ttss::TtssClient;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PassageWelcome {
@@ -25,6 +35,7 @@ pub struct PassageWelcome {
 pub struct PassageActual {
     #[serde(rename = "actualTime")]
     actual_time: Option<String>,
+    #[serde(rename = "plannedTime")]
     planned_time: Option<String>,
     status: PassageStatus,
     stop: PassageStop,
@@ -39,7 +50,7 @@ pub struct PassageStop {
     short_name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum PassageStatus {
     #[serde(rename = "DEPARTED")]
     Departed,
@@ -51,39 +62,75 @@ pub enum PassageStatus {
     Stopping,
 }
 
-async fn fetch_tram_passage(s: String) -> reqwest::Result<PassageWelcome> {
-    println!("fetch");
-    let fs = format!("https://mpk.jacekk.net/proxy_tram.php/services/tripInfo/tripPassages?tripId={}&mode=departure", s);
-    let url = reqwest::Url::parse(&fs).unwrap();
-
-    reqwest::get(url).await?.json::<PassageWelcome>().await
-}
-
 #[derive(Debug)]
 pub struct TripMeta {
     pub route_name: String,
     pub direction_text: String,
 }
 
-#[derive(Debug)]
+/// When a trip is given up and where its trajectory goes then.
+#[derive(Clone)]
+pub struct Retirement {
+    /// Trips without a successful update for this long are retired.
+    pub idle_ttl: Duration,
+    pub sink: Arc<dyn TrajectorySink>,
+}
+
+impl Default for Retirement {
+    fn default() -> Retirement {
+        Retirement {
+            idle_ttl: Duration::from_secs(1800),
+            sink: Arc::new(LogSink),
+        }
+    }
+}
+
 pub struct Trip {
     id: String,
+    /// Fetches the trip's passages every minute; without it the trip only
+    /// moves on passages sent to it.
+    client: Option<Arc<dyn TtssClient>>,
+    clock: SharedClock,
     trip_meta: Option<TripMeta>,
     stop_seq: Option<u32>,
     next_stop: Option<String>,
+    /// Sequence number of the last stop turned into fragment events.
+    last_departed_seq: Option<u32>,
+    /// Dated by the first stop of the trip, so that departures either side
+    /// of the service day start share it.
+    key: Option<TripKey>,
     last_progress_time: Option<std::time::Instant>,
     update_time: Option<std::time::Instant>,
+    registered: std::time::Instant,
+    trajectory: Vec<TrajectoryStop>,
+    vehicle_id: Option<String>,
+    retirement: Retirement,
+    retired: bool,
 }
 
 impl Trip {
-    pub fn new(id: String) -> Trip {
+    pub fn new(
+        id: String,
+        client: Option<Arc<dyn TtssClient>>,
+        clock: SharedClock,
+        retirement: Retirement,
+    ) -> Trip {
         Trip {
-            id: id,
+            id,
+            client,
+            registered: clock.instant(),
+            clock,
             trip_meta: None,
             stop_seq: None,
             next_stop: None,
+            last_departed_seq: None,
+            key: None,
             last_progress_time: None,
             update_time: None,
+            trajectory: Vec::new(),
+            vehicle_id: None,
+            retirement,
+            retired: false,
         }
     }
 }
@@ -92,14 +139,34 @@ impl Actor for Trip {
     type Context = Context<Trip>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.notify(SelfFetchUpdateRequest);
+        if self.retired {
+            return;
+        }
+        ctx.notify(CheckIdle);
+        if self.client.is_some() {
+            ctx.notify(SelfFetchUpdateRequest);
+        }
+    }
+}
+
+impl Supervised for Trip {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        if self.retired {
+            return;
+        }
+        println!("Restarting trip {}", self.id);
+
+        PollerHealth::from_registry().do_send(PollerRestarted {
+            kind: PollerKind::Trip,
+            id: self.id.clone(),
+        });
     }
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
-struct DirectPassageSync {
-    passage: PassageWelcome,
+pub struct DirectPassageSync {
+    pub passage: PassageWelcome,
 }
 
 #[derive(Message)]
@@ -110,22 +177,122 @@ impl Handler<SelfFetchUpdateRequest> for Trip {
     type Result = ();
 
     fn handle(&mut self, _msg: SelfFetchUpdateRequest, ctx: &mut Context<Self>) {
-        let z = String::from(&self.id);
-        let x = actix::fut::wrap_future::<_, Self>(fetch_tram_passage(z));
+        if self.retired {
+            return;
+        }
+
+        let client = match &self.client {
+            Some(client) => client,
+            None => return,
+        };
+        let x =
+            actix::fut::wrap_future::<_, Self>(client.trip_passages(&self.id, Priority::Normal));
         ctx.wait(x.map(|_result, _actor, _ctx| {
-            _ctx.notify(DirectPassageSync {
-                passage: _result.unwrap(),
-            });
+            PollerHealth::from_registry().do_send(FetchOutcome::new(
+                PollerKind::Trip,
+                &_actor.id,
+                _actor.clock.now(),
+                &_result,
+            ));
+
+            match _result {
+                Ok(passage) => _ctx.notify(DirectPassageSync { passage }),
+                Err(e) => println!("{} fetch failed: {}", _actor.id, e),
+            }
         }));
         ctx.notify_later(SelfFetchUpdateRequest, Duration::from_secs(60));
     }
 }
 
+/// Checked apart from fetching, as trips fed from outside go idle too.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct CheckIdle;
+
+impl Handler<CheckIdle> for Trip {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CheckIdle, ctx: &mut Context<Self>) {
+        if self.retired {
+            return;
+        }
+        let idle = self.clock.instant() - self.update_time.unwrap_or(self.registered);
+        if idle > self.retirement.idle_ttl {
+            self.retire("idle", ctx);
+            return;
+        }
+
+        ctx.notify_later(CheckIdle, Duration::from_secs(60));
+    }
+}
+
+/// Vehicle serving the trip, as seen in stop passages.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TripVehicle {
+    pub vehicle_id: String,
+}
+
+impl Handler<TripVehicle> for Trip {
+    type Result = ();
+
+    fn handle(&mut self, msg: TripVehicle, _ctx: &mut Context<Self>) {
+        self.vehicle_id = Some(msg.vehicle_id);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "TripSummary")]
+pub struct GetTripSummary;
+
+impl Handler<GetTripSummary> for Trip {
+    type Result = MessageResult<GetTripSummary>;
+
+    fn handle(&mut self, _msg: GetTripSummary, _ctx: &mut Context<Self>) -> Self::Result {
+        let last_departed = self
+            .trajectory
+            .iter()
+            .rev()
+            .find(|s| s.status == PassageStatus::Departed);
+
+        MessageResult(TripSummary {
+            trip_id: self.id.clone(),
+            route_name: self.trip_meta.as_ref().map(|m| m.route_name.clone()),
+            direction: self.trip_meta.as_ref().map(|m| m.direction_text.clone()),
+            vehicle_id: self.vehicle_id.clone(),
+            next_stop: self.next_stop.clone(),
+            delay_secs: last_departed.and_then(|s| s.delay_secs),
+        })
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Trajectory")]
+pub struct GetTrajectory;
+
+impl Handler<GetTrajectory> for Trip {
+    type Result = MessageResult<GetTrajectory>;
+
+    fn handle(&mut self, _msg: GetTrajectory, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.trajectory())
+    }
+}
+
 impl Handler<DirectPassageSync> for Trip {
     type Result = ();
 
-    fn handle(&mut self, _msg: DirectPassageSync, _ctx: &mut Context<Self>) {
-        self.update_time = Some(std::time::Instant::now());
+    fn handle(&mut self, msg: DirectPassageSync, ctx: &mut Context<Self>) {
+        if self.retired {
+            return;
+        }
+        poller_health::catch_panic(self, ctx, |actor, ctx| actor.sync_passage(msg, ctx));
+    }
+}
+
+impl Trip {
+    fn sync_passage(&mut self, _msg: DirectPassageSync, _ctx: &mut Context<Self>) {
+        let now = self.clock.now();
+        self.update_time = Some(self.clock.instant());
 
         if self.trip_meta.is_none() {
             self.trip_meta = Some(TripMeta {
@@ -135,67 +302,380 @@ impl Handler<DirectPassageSync> for Trip {
         }
 
         if let Some(actual_passage) = _msg.passage.actual.first() {
-            let new_stop_seq = actual_passage.stop_seq_num.parse::<u32>().unwrap();
-
-            if Some(new_stop_seq) != self.stop_seq {
-                let new_stop = &actual_passage.stop.name;
-
-                println!(
-                    "{} {:?} made progress: {:?} -> {:?}",
-                    &self.id, &self.trip_meta, self.next_stop, new_stop
-                );
-
-                self.last_progress_time = self.update_time;
-                self.stop_seq = Some(new_stop_seq);
-                self.next_stop = Some(String::from(new_stop));
-
-                let rr = route_fragment_registry::RouteFragmentRegistry::from_registry();
-
-                let f = rr
-                    .send(route_fragment_registry::GetRouteFragment::new(
-                        String::from(&actual_passage.stop.id),
-                    ))
-                    .into_actor(self)
-                    .map(|result, actor, _ctx| {
-                        result.unwrap().do_send(
-                            route_fragment_registry::route_fragment::FragmentEntryEvent {
-                                trip_id: String::from(&actor.id),
-                                instant: std::time::Instant::now(),
-                                time: Local::now().time(),
-                            },
-                        )
-                    });
-
-                _ctx.spawn(f);
+            match actual_passage.stop_seq_num.parse::<u32>() {
+                Ok(new_stop_seq) if Some(new_stop_seq) != self.stop_seq => {
+                    let new_stop = &actual_passage.stop.name;
+
+                    println!(
+                        "{} {:?} made progress: {:?} -> {:?}",
+                        &self.id, &self.trip_meta, self.next_stop, new_stop
+                    );
+
+                    self.last_progress_time = self.update_time;
+                    self.stop_seq = Some(new_stop_seq);
+                    self.next_stop = Some(String::from(new_stop));
+                }
+                Ok(_) => {}
+                Err(e) => println!(
+                    "{} skipping passage with stop seq {:?}: {}",
+                    self.id, actual_passage.stop_seq_num, e
+                ),
             }
+        }
+
+        self.record_trajectory(_msg.passage.old.iter().chain(&_msg.passage.actual), now);
+        self.sync_departures();
 
-            if let Some(old_stop) = _msg.passage.old.last() {
-                let ttime = old_stop
-                    .actual_time
+        let departures: Vec<_> = _msg
+            .passage
+            .actual
+            .iter()
+            .filter(|p| !matches!(p.status, PassageStatus::Departed))
+            .filter_map(|p| {
+                p.actual_time
                     .as_ref()
-                    .map(|x| NaiveTime::parse_from_str(&x, "%H:%M").unwrap())
-                    .unwrap();
-
-                println!("Old passage: {}", old_stop.stop.id);
-                let z = route_fragment_registry::RouteFragmentRegistry::from_registry()
-                    .send(route_fragment_registry::GetRouteFragment::new(
-                        String::from(&old_stop.stop.id),
-                    ))
-                    .into_actor(self)
-                    .map(move |result, actor, _ctx| {
-                        result.unwrap().do_send(
-                            route_fragment_registry::route_fragment::FragmentLeaveEvent {
-                                trip_id: String::from(&actor.id),
-                                instant: std::time::Instant::now(),
-                                time: ttime,
-                            },
-                        )
-                    });
-
-                _ctx.spawn(z);
+                    .or(p.planned_time.as_ref())
+                    .and_then(|x| NaiveTime::parse_from_str(x, "%H:%M").ok())
+                    .and_then(|x| timestamp::resolve_clock_time(&now, x))
+                    .map(|time| (p.stop.id.clone(), time))
+            })
+            .collect();
+        if !departures.is_empty() {
+            StopRegistry::from_registry().do_send(TripPredictions {
+                trip_id: self.id.clone(),
+                departures,
+            });
+        }
+
+        println!(
+            "{} {:?} seq {:?} next {:?}",
+            self.id, self.trip_meta, self.stop_seq, self.next_stop
+        );
+
+        let actual = &_msg.passage.actual;
+        if actual
+            .iter()
+            .all(|p| matches!(p.status, PassageStatus::Departed))
+        {
+            self.retire("finished", _ctx);
+        }
+    }
+
+    fn trajectory(&self) -> Trajectory {
+        Trajectory {
+            trip_id: self.id.clone(),
+            route_name: self.trip_meta.as_ref().map(|m| m.route_name.clone()),
+            direction: self.trip_meta.as_ref().map(|m| m.direction_text.clone()),
+            vehicle_id: self.vehicle_id.clone(),
+            stops: self.trajectory.clone(),
+        }
+    }
+
+    /// Hands the trajectory to the sink and leaves the registry; the actor
+    /// stops once nothing refers to it any more.
+    fn retire(&mut self, reason: &str, ctx: &mut Context<Self>) {
+        println!("Retiring trip {} ({})", self.id, reason);
+        self.retired = true;
+
+        self.retirement.sink.finished(&self.trajectory());
+
+        PollerHealth::from_registry().do_send(PollerRetired {
+            kind: PollerKind::Trip,
+            id: self.id.clone(),
+        });
+        let now = self.clock.now();
+        TripRegistry::from_registry().do_send(TripRetired {
+            id: self.id.clone(),
+            key: self
+                .key
+                .clone()
+                .unwrap_or_else(|| TripKey::new(&self.id, &now)),
+            time: now,
+            addr: ctx.address(),
+        });
+    }
+}
+
+impl Trip {
+    /// Merges passages into the trajectory, keeping it ordered by stop
+    /// sequence.
+    fn record_trajectory<'a>(
+        &mut self,
+        passages: impl Iterator<Item = &'a PassageActual>,
+        now: timestamp::Timestamp,
+    ) {
+        let resolve = |time: &Option<String>| {
+            time.as_ref()
+                .and_then(|x| NaiveTime::parse_from_str(x, "%H:%M").ok())
+                .and_then(|x| timestamp::resolve_clock_time(&now, x))
+        };
+
+        for p in passages {
+            let seq = match p.stop_seq_num.parse::<u32>() {
+                Ok(seq) => seq,
+                Err(_) => continue,
+            };
+            let planned_time = resolve(&p.planned_time);
+            let actual_time = resolve(&p.actual_time);
+
+            let idx = match self.trajectory.binary_search_by_key(&seq, |s| s.seq) {
+                Ok(idx) => idx,
+                Err(idx) => {
+                    self.trajectory.insert(
+                        idx,
+                        TrajectoryStop {
+                            stop_id: p.stop.id.clone(),
+                            stop_name: p.stop.name.clone(),
+                            seq,
+                            planned_time: None,
+                            actual_time: None,
+                            status: p.status,
+                            delay_secs: None,
+                        },
+                    );
+                    idx
+                }
+            };
+
+            let stop = &mut self.trajectory[idx];
+            stop.status = p.status;
+            stop.planned_time = planned_time.or(stop.planned_time);
+            stop.actual_time = actual_time.or(stop.actual_time);
+            stop.delay_secs = match (stop.planned_time, stop.actual_time) {
+                (Some(planned), Some(actual)) => Some((actual - planned).num_seconds()),
+                _ => None,
+            };
+        }
+    }
+
+    /// Reports newly departed stops, measured the same way as by stop
+    /// polling: a fragment spans from the departure at one stop to the
+    /// departure from the next one.
+    fn sync_departures(&mut self) {
+        let registry = route_fragment_registry::RouteFragmentRegistry::from_registry();
+        if self.key.is_none() {
+            let start = self
+                .trajectory
+                .iter()
+                .find_map(|stop| stop.planned_time.or(stop.actual_time));
+            self.key = start.map(|start| TripKey::new(&self.id, &start));
+        }
+        let key = match &self.key {
+            Some(key) => key.clone(),
+            None => return,
+        };
+
+        for (i, stop) in self.trajectory.iter().enumerate() {
+            let time = match (stop.status, stop.actual_time) {
+                (PassageStatus::Departed, Some(time)) => time,
+                _ => continue,
+            };
+            if matches!(self.last_departed_seq, Some(last) if stop.seq <= last) {
+                continue;
             }
+            println!("{} departed {} at {}", self.id, stop.stop_id, time);
+
+            registry.do_send(route_fragment_registry::Departure {
+                stop_id: stop.stop_id.clone(),
+                stop_name: Some(stop.stop_name.clone()),
+                next_stop_id: self.trajectory.get(i + 1).map(|next| next.stop_id.clone()),
+                trip: key.clone(),
+                source: route_fragment::Source::Trip,
+                passage: format!("{}@{}", self.id, stop.stop_id),
+                age_secs: (self.clock.now() - time).num_seconds().max(0) as u64,
+                line: self.trip_meta.as_ref().map(|meta| route_fragment::Line {
+                    route: meta.route_name.clone(),
+                    direction: meta.direction_text.clone(),
+                }),
+                vehicle_id: self.vehicle_id.clone(),
+                time,
+            });
+
+            self.last_departed_seq = Some(stop.seq);
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::poller_health::GetPollerStatus;
+
+    fn passage(stop_seq_num: &str) -> PassageWelcome {
+        serde_json::from_value(serde_json::json!({
+            "actual": [{
+                "actualTime": "12:01",
+                "plannedTime": "12:01",
+                "status": "PREDICTED",
+                "stop": { "id": "1", "name": "A", "shortName": "1" },
+                "stop_seq_num": stop_seq_num,
+            }],
+            "directionText": "B",
+            "old": [],
+            "routeName": "1",
+        }))
+        .unwrap()
+    }
+
+    /// Panics the first time it is asked for the time.
+    struct PanicOnceClock(std::sync::atomic::AtomicBool);
+
+    impl crate::clock::Clock for PanicOnceClock {
+        fn now(&self) -> timestamp::Timestamp {
+            if !self.0.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                panic!("clock failure");
+            }
+            crate::clock::SystemClock.now()
+        }
+
+        fn instant(&self) -> std::time::Instant {
+            std::time::Instant::now()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn trip_is_restarted_after_panic() {
+        let trip = Supervisor::start(|_| {
+            Trip::new(
+                String::from("7"),
+                None,
+                Arc::new(PanicOnceClock(Default::default())),
+                Retirement::default(),
+            )
+        });
+
+        trip.send(DirectPassageSync {
+            passage: passage("3"),
+        })
+        .await
+        .unwrap();
+        trip.send(DirectPassageSync {
+            passage: passage("3"),
+        })
+        .await
+        .unwrap();
+
+        let statuses = PollerHealth::from_registry()
+            .send(GetPollerStatus)
+            .await
+            .unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].id, "7");
+        assert_eq!(statuses[0].restarts, 1);
+    }
 
-        println!("{:?}", self);
+    #[actix_rt::test]
+    async fn malformed_stop_seq_is_skipped() {
+        let trip = Trip::new(
+            String::from("8"),
+            None,
+            crate::clock::system(),
+            Retirement::default(),
+        )
+        .start();
+
+        trip.send(DirectPassageSync {
+            passage: passage("not a number"),
+        })
+        .await
+        .unwrap();
+        assert_eq!(trip.send(GetTripSummary).await.unwrap().next_stop, None);
+
+        trip.send(DirectPassageSync {
+            passage: passage("3"),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            trip.send(GetTripSummary).await.unwrap().next_stop,
+            Some(String::from("A"))
+        );
+
+        let statuses = PollerHealth::from_registry()
+            .send(GetPollerStatus)
+            .await
+            .unwrap();
+        assert!(statuses.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn trips_without_client_retire_when_idle() {
+        use chrono::TimeZone;
+
+        use crate::clock::SimulatedClock;
+        use crate::timestamp::TIMEZONE;
+
+        #[derive(Default)]
+        struct CountingSink(std::sync::atomic::AtomicUsize);
+
+        impl TrajectorySink for CountingSink {
+            fn finished(&self, _trajectory: &Trajectory) {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+
+        let start = TIMEZONE.ymd(2020, 3, 16).and_hms(12, 0, 0);
+        let clock = Arc::new(SimulatedClock::new(start));
+        let sink = Arc::new(CountingSink::default());
+        let trip = Trip::new(
+            String::from("9"),
+            None,
+            clock.clone(),
+            Retirement {
+                idle_ttl: Duration::from_secs(60),
+                sink: sink.clone(),
+            },
+        );
+        clock.set(start + chrono::Duration::seconds(120));
+
+        let trip = trip.start();
+        trip.send(GetTripSummary).await.unwrap();
+        assert_eq!(sink.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn departed_stops_pair_into_fragment_times() {
+        use chrono::TimeZone;
+
+        use crate::clock::SimulatedClock;
+        use crate::fake_ttss::{test_scenario, FakeTtss};
+        use crate::route_fragment::{Edge, FragmentStatusRequest};
+        use crate::route_fragment_registry::{ListRouteFragments, RouteFragmentRegistry};
+        use crate::timestamp::TIMEZONE;
+
+        let fake = FakeTtss::new(test_scenario());
+        let clock = Arc::new(SimulatedClock::new(
+            TIMEZONE.ymd(2020, 3, 16).and_hms(12, 0, 0),
+        ));
+        actix::Registry::set(RouteFragmentRegistry::new(clock.clone()).start());
+        let trip_id = "8059232507169530113";
+        let trip = Supervisor::start(move |_| {
+            Trip::new(String::from(trip_id), None, clock, Retirement::default())
+        });
+
+        for &t in &[100, 200] {
+            fake.clock.advance(t - fake.clock.now());
+            let passage = serde_json::from_value(fake.trip_passages(trip_id).unwrap()).unwrap();
+            trip.send(DirectPassageSync { passage }).await.unwrap();
+        }
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+
+        let fragments: std::collections::HashMap<_, _> = RouteFragmentRegistry::from_registry()
+            .send(ListRouteFragments)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        let stops = test_scenario().stops;
+        let mut times = Vec::new();
+        for pair in stops.windows(2) {
+            let stats = fragments[&Edge::new(&pair[0].id, &pair[1].id)]
+                .send(FragmentStatusRequest)
+                .await
+                .unwrap();
+            times.push(stats.time);
+        }
+        assert_eq!(times, vec![Some(60), Some(120)]);
     }
 }
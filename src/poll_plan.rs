@@ -0,0 +1,181 @@
+use chrono::Duration;
+use serde::Deserialize;
+
+use crate::scheduler::Priority;
+use crate::timestamp::Timestamp;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PollingConfig {
+    pub min_interval_secs: i64,
+    /// Longest wait between polls during service hours.
+    pub max_interval_secs: i64,
+    /// Delay after an expected departure, so it already shows up as old.
+    pub departure_grace_secs: i64,
+    /// Departures this close to the first one are covered by a single poll.
+    pub cluster_secs: i64,
+    /// How early to resume polling before the first passage of the day.
+    pub service_lead_secs: i64,
+    /// Polls due this soon go ahead of others in the request scheduler.
+    pub imminent_secs: i64,
+}
+
+impl Default for PollingConfig {
+    fn default() -> PollingConfig {
+        PollingConfig {
+            min_interval_secs: 15,
+            max_interval_secs: 300,
+            departure_grace_secs: 10,
+            cluster_secs: 60,
+            service_lead_secs: 300,
+            imminent_secs: 30,
+        }
+    }
+}
+
+/// First and last passage of the day at a stop, as reported by TTSS.
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceWindow {
+    pub first: Timestamp,
+    pub last: Timestamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PollReason {
+    /// Timed to see this departure among the old passages.
+    Departure(Timestamp),
+    Idle,
+    /// No service until the given first passage.
+    Overnight(Timestamp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollPlan {
+    pub delay: std::time::Duration,
+    pub priority: Priority,
+    pub reason: PollReason,
+}
+
+/// Plans the next poll of a stop from its known upcoming departures.
+pub fn plan(
+    config: &PollingConfig,
+    now: Timestamp,
+    upcoming: &[Timestamp],
+    window: Option<&ServiceWindow>,
+) -> PollPlan {
+    let min = Duration::seconds(config.min_interval_secs);
+    let max = Duration::seconds(config.max_interval_secs);
+    let clamp = |delay: Duration| delay.max(min).min(max);
+
+    let mut upcoming = upcoming.to_vec();
+    upcoming.sort();
+
+    if let Some(&first) = upcoming.first() {
+        let cluster_end = upcoming
+            .iter()
+            .take_while(|&&t| t - first <= Duration::seconds(config.cluster_secs))
+            .last()
+            .copied()
+            .unwrap_or(first);
+        let delay = cluster_end + Duration::seconds(config.departure_grace_secs) - now;
+
+        return PollPlan {
+            delay: to_std(clamp(delay)),
+            priority: if delay <= Duration::seconds(config.imminent_secs) {
+                Priority::Imminent
+            } else {
+                Priority::Normal
+            },
+            reason: PollReason::Departure(cluster_end),
+        };
+    }
+
+    if let Some(window) = window {
+        let lead = Duration::seconds(config.service_lead_secs);
+        let next_first = if now > window.last {
+            Some(window.first + Duration::days(1))
+        } else if now < window.first - lead {
+            Some(window.first)
+        } else {
+            None
+        };
+
+        if let Some(next_first) = next_first.filter(|&t| t - lead - now > max) {
+            return PollPlan {
+                delay: to_std(next_first - lead - now),
+                priority: Priority::Normal,
+                reason: PollReason::Overnight(next_first),
+            };
+        }
+    }
+
+    PollPlan {
+        delay: to_std(max.max(min)),
+        priority: Priority::Normal,
+        reason: PollReason::Idle,
+    }
+}
+
+fn to_std(delay: Duration) -> std::time::Duration {
+    delay.to_std().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    use crate::timestamp::TIMEZONE;
+
+    fn at(h: u32, m: u32, s: u32) -> Timestamp {
+        TIMEZONE.ymd(2020, 3, 16).and_hms(h, m, s)
+    }
+
+    fn secs(plan: &PollPlan) -> u64 {
+        plan.delay.as_secs()
+    }
+
+    #[test]
+    fn departures_close_together_share_a_poll() {
+        let config = PollingConfig::default();
+        let upcoming = [at(12, 1, 40), at(12, 1, 0), at(12, 5, 0)];
+
+        let plan = plan(&config, at(12, 0, 0), &upcoming, None);
+        assert_eq!(secs(&plan), 110);
+        assert_eq!(plan.priority, Priority::Normal);
+        assert_eq!(plan.reason, PollReason::Departure(at(12, 1, 40)));
+    }
+
+    #[test]
+    fn delays_are_bounded() {
+        let config = PollingConfig::default();
+
+        let soon = plan(&config, at(12, 0, 0), &[at(11, 59, 0)], None);
+        assert_eq!(secs(&soon), 15);
+        assert_eq!(soon.priority, Priority::Imminent);
+
+        let far = plan(&config, at(12, 0, 0), &[at(12, 30, 0)], None);
+        assert_eq!(secs(&far), 300);
+        assert_eq!(far.priority, Priority::Normal);
+    }
+
+    #[test]
+    fn polling_is_suspended_after_last_passage() {
+        let config = PollingConfig::default();
+        let window = ServiceWindow {
+            first: at(4, 30, 0),
+            last: at(23, 40, 0),
+        };
+
+        let night = plan(&config, at(23, 50, 0), &[], Some(&window));
+        assert_eq!(secs(&night), (4 * 60 + 35) * 60);
+        assert_eq!(
+            night.reason,
+            PollReason::Overnight(window.first + Duration::days(1))
+        );
+
+        let day = plan(&config, at(12, 0, 0), &[], Some(&window));
+        assert_eq!(day.reason, PollReason::Idle);
+    }
+}
@@ -0,0 +1,186 @@
+use actix::prelude::*;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::timestamp::{self, Timestamp};
+use crate::ttss;
+
+/// Consecutive failures after which a poller is reported as failing.
+const FAILING_THRESHOLD: u32 = 3;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PollerKind {
+    Stop,
+    Trip,
+}
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct FailureCounts {
+    pub network: u64,
+    pub status: u64,
+    pub decode: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PollerStatus {
+    pub kind: PollerKind,
+    pub id: String,
+    #[serde(serialize_with = "timestamp::serialize_option")]
+    pub last_success: Option<Timestamp>,
+    #[serde(serialize_with = "timestamp::serialize_option")]
+    pub last_failure: Option<Timestamp>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    pub failures: FailureCounts,
+    pub failing: bool,
+    pub restarts: u32,
+}
+
+impl PollerStatus {
+    fn new(kind: PollerKind, id: String) -> PollerStatus {
+        PollerStatus {
+            kind,
+            id,
+            last_success: None,
+            last_failure: None,
+            last_error: None,
+            consecutive_failures: 0,
+            failures: FailureCounts::default(),
+            failing: false,
+            restarts: 0,
+        }
+    }
+}
+
+/// Collects fetch outcomes of all stop and trip pollers.
+#[derive(Default)]
+pub struct PollerHealth {
+    pollers: HashMap<(PollerKind, String), PollerStatus>,
+}
+
+impl Actor for PollerHealth {
+    type Context = Context<PollerHealth>;
+}
+
+impl Supervised for PollerHealth {}
+impl ArbiterService for PollerHealth {}
+
+impl PollerHealth {
+    fn status(&mut self, kind: PollerKind, id: String) -> &mut PollerStatus {
+        self.pollers
+            .entry((kind, id.clone()))
+            .or_insert_with(|| PollerStatus::new(kind, id))
+    }
+}
+
+/// Runs `f` on a supervised actor, stopping the actor if `f` panics so that
+/// its supervisor restarts it.
+pub fn catch_panic<A, F>(actor: &mut A, ctx: &mut Context<A>, f: F)
+where
+    A: Actor<Context = Context<A>> + Supervised,
+    F: FnOnce(&mut A, &mut Context<A>),
+{
+    if panic::catch_unwind(AssertUnwindSafe(|| f(actor, ctx))).is_err() {
+        ctx.stop();
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct FetchOutcome {
+    pub kind: PollerKind,
+    pub id: String,
+    pub time: Timestamp,
+    pub error: Option<(ttss::ErrorKind, String)>,
+}
+
+impl FetchOutcome {
+    pub fn new<T>(
+        kind: PollerKind,
+        id: &str,
+        time: Timestamp,
+        result: &ttss::FetchResult<T>,
+    ) -> FetchOutcome {
+        FetchOutcome {
+            kind,
+            id: String::from(id),
+            time,
+            error: result.as_ref().err().map(|e| (e.kind(), e.to_string())),
+        }
+    }
+}
+
+impl Handler<FetchOutcome> for PollerHealth {
+    type Result = ();
+
+    fn handle(&mut self, msg: FetchOutcome, _ctx: &mut Context<Self>) {
+        let status = self.status(msg.kind, msg.id);
+
+        match msg.error {
+            None => {
+                status.last_success = Some(msg.time);
+                status.consecutive_failures = 0;
+            }
+            Some((kind, error)) => {
+                status.last_failure = Some(msg.time);
+                status.last_error = Some(error);
+                status.consecutive_failures += 1;
+
+                match kind {
+                    ttss::ErrorKind::Network => status.failures.network += 1,
+                    ttss::ErrorKind::Status => status.failures.status += 1,
+                    ttss::ErrorKind::Decode => status.failures.decode += 1,
+                }
+            }
+        }
+        status.failing = status.consecutive_failures >= FAILING_THRESHOLD;
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PollerRestarted {
+    pub kind: PollerKind,
+    pub id: String,
+}
+
+impl Handler<PollerRestarted> for PollerHealth {
+    type Result = ();
+
+    fn handle(&mut self, msg: PollerRestarted, _ctx: &mut Context<Self>) {
+        self.status(msg.kind, msg.id).restarts += 1;
+    }
+}
+
+/// Poller that ended on purpose and should no longer be reported.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PollerRetired {
+    pub kind: PollerKind,
+    pub id: String,
+}
+
+impl Handler<PollerRetired> for PollerHealth {
+    type Result = ();
+
+    fn handle(&mut self, msg: PollerRetired, _ctx: &mut Context<Self>) {
+        self.pollers.remove(&(msg.kind, msg.id));
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<PollerStatus>")]
+pub struct GetPollerStatus;
+
+impl Handler<GetPollerStatus> for PollerHealth {
+    type Result = MessageResult<GetPollerStatus>;
+
+    fn handle(&mut self, _msg: GetPollerStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut statuses: Vec<_> = self.pollers.values().cloned().collect();
+        statuses.sort_by(|a, b| (a.kind as u8, &a.id).cmp(&(b.kind as u8, &b.id)));
+
+        MessageResult(statuses)
+    }
+}
@@ -0,0 +1,219 @@
+use actix::prelude::*;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::capture::{self, CaptureRecord, Endpoint};
+use crate::clock::{Clock, SharedClock, SimulatedClock};
+use crate::config::Config;
+use crate::network::Network;
+use crate::passage::{DirectPassageSync, PassageWelcome};
+use crate::route_fragment::LineFilter;
+use crate::route_fragment::RouteFragmentStats;
+use crate::route_fragment_registry::RouteFragmentRegistry;
+use crate::timestamp::{self, Timestamp, TIMEZONE};
+use crate::trip_registry::{RegisterTrip, TripRegistry};
+use crate::{StopPassageSync, StopState, Welcome};
+
+/// Simulated seconds between two entries of the emitted stats history.
+const HISTORY_INTERVAL_SECS: i64 = 60;
+
+#[derive(Serialize)]
+struct HistoryEntry<'a> {
+    #[serde(serialize_with = "timestamp::serialize")]
+    time: Timestamp,
+    fragments: &'a [RouteFragmentStats],
+}
+
+/// Processed only after the actor finished all earlier work.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Settle;
+
+impl Handler<Settle> for StopState {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Settle, _ctx: &mut Context<Self>) {}
+}
+
+async fn settle(stops: &HashMap<String, Addr<StopState>>) -> io::Result<()> {
+    for stop in stops.values() {
+        stop.send(Settle).await.map_err(io::Error::other)?;
+    }
+    actix_rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+
+    Ok(())
+}
+
+async fn feed(
+    record: CaptureRecord,
+    stops: &HashMap<String, Addr<StopState>>,
+    clock: &SharedClock,
+    measure_trips: bool,
+) -> io::Result<()> {
+    match record.endpoint {
+        Endpoint::StopPassages => {
+            let stop = match stops.get(&record.id) {
+                Some(stop) => stop,
+                None => return Ok(()),
+            };
+
+            match serde_json::from_str::<Welcome>(&record.body) {
+                Ok(passage) => stop
+                    .send(StopPassageSync { passage })
+                    .await
+                    .map_err(io::Error::other),
+                Err(e) => {
+                    println!("Skipping stop passage {}: {}", record.id, e);
+                    Ok(())
+                }
+            }
+        }
+        Endpoint::TripPassages if !measure_trips => Ok(()),
+        Endpoint::TripPassages => match serde_json::from_str::<PassageWelcome>(&record.body) {
+            Ok(passage) => {
+                let trip = TripRegistry::from_registry()
+                    .send(RegisterTrip::new(record.id, None, clock.clone()))
+                    .await
+                    .map_err(io::Error::other)?;
+
+                match trip {
+                    Some(trip) => trip
+                        .send(DirectPassageSync { passage })
+                        .await
+                        .map_err(io::Error::other),
+                    None => Ok(()),
+                }
+            }
+            Err(e) => {
+                println!("Skipping trip passage {}: {}", record.id, e);
+                Ok(())
+            }
+        },
+    }
+}
+
+/// Feeds a capture through the actor system in timestamp order and writes
+/// periodic fragment stats as JSON lines to `history_path`.
+pub async fn run(config: &Config, capture_path: &str, history_path: &str) -> io::Result<()> {
+    let mut records = capture::read_capture(capture_path)?;
+    records.sort_by_key(|r| r.fetched_at);
+
+    let start = match records.first() {
+        Some(record) => record.fetched_at.with_timezone(&TIMEZONE),
+        None => {
+            println!("Capture {} is empty", capture_path);
+            return Ok(());
+        }
+    };
+    println!(
+        "Replaying {} responses from {} into {}",
+        records.len(),
+        capture_path,
+        history_path
+    );
+
+    let sim_clock = Arc::new(SimulatedClock::new(start));
+    let clock: SharedClock = sim_clock.clone();
+
+    let registry = RouteFragmentRegistry::new(clock.clone())
+        .with_network(Network::from_config(config))
+        .with_history(config.history.clone())
+        .start();
+    actix::Registry::set(registry.clone());
+
+    let stops: HashMap<_, _> = crate::spawn_stop_pollers(config, None, clock.clone())
+        .into_iter()
+        .collect();
+
+    let mut history = File::create(history_path)?;
+    let mut emit = |time: Timestamp, fragments: &[RouteFragmentStats]| {
+        serde_json::to_writer(&mut history, &HistoryEntry { time, fragments })
+            .map_err(io::Error::from)
+            .and_then(|_| history.write_all(b"\n"))
+    };
+
+    let mut next_emit = start + chrono::Duration::seconds(HISTORY_INTERVAL_SECS);
+    for record in records {
+        let fetched_at = record.fetched_at.with_timezone(&TIMEZONE);
+        if fetched_at >= next_emit {
+            settle(&stops).await?;
+            let stats = crate::collect_stats(&registry, config, &LineFilter::default())
+                .await
+                .map_err(io::Error::other)?;
+            emit(sim_clock.now(), &stats)?;
+
+            next_emit = fetched_at + chrono::Duration::seconds(HISTORY_INTERVAL_SECS);
+        }
+
+        sim_clock.set(fetched_at);
+        if record.status != 200 {
+            continue;
+        }
+
+        feed(record, &stops, &clock, config.measurement.uses_trips()).await?;
+    }
+
+    settle(&stops).await?;
+    let stats = crate::collect_stats(&registry, config, &LineFilter::default())
+        .await
+        .map_err(io::Error::other)?;
+    emit(sim_clock.now(), &stats)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::{Local, TimeZone};
+
+    use crate::capture::Recorder;
+    use crate::fake_ttss::{test_config, test_scenario, FakeTtss};
+    use crate::test_dir::TestDir;
+
+    #[actix_rt::test]
+    async fn replay_measures_fragments_in_simulated_time() {
+        let dir = TestDir::new();
+        let capture_path = &dir.path("capture.jsonl.gz");
+        let history_path = &dir.path("history.jsonl");
+
+        let fake = FakeTtss::new(test_scenario());
+        let start = Local.ymd(2020, 3, 16).and_hms(12, 0, 0);
+        {
+            let recorder = Recorder::open(capture_path).unwrap();
+            for &t in &[0, 100, 200] {
+                fake.clock.advance(t - fake.clock.now());
+                for stop in &test_scenario().stops {
+                    recorder.record(&CaptureRecord {
+                        fetched_at: start + chrono::Duration::seconds(t),
+                        endpoint: Endpoint::StopPassages,
+                        id: stop.id.clone(),
+                        status: 200,
+                        body: fake.stop_passages(&stop.id).unwrap().to_string(),
+                    });
+                }
+            }
+        }
+
+        let config = test_config(String::new());
+        run(&config, capture_path, history_path).await.unwrap();
+
+        let history = std::fs::read_to_string(history_path).unwrap();
+        let last: serde_json::Value =
+            serde_json::from_str(history.lines().last().unwrap()).unwrap();
+        let times: Vec<_> = last["fragments"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["time"].as_u64())
+            .collect();
+        assert_eq!(times, vec![Some(60), Some(90)]);
+        assert_eq!(history.lines().count(), 3);
+    }
+}
@@ -0,0 +1,462 @@
+use actix::prelude::*;
+use chrono::{Offset, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::SharedClock;
+use crate::history::percentile;
+use crate::route_fragment::{Edge, Line, LineFilter};
+use crate::storage::{SampleRecord, SampleStore, SharedStore};
+use crate::timestamp::{Timestamp, TIMEZONE};
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Samples are kept this long, then rolled up into buckets.
+    pub raw_days: u64,
+    /// Rolled up buckets are kept this long.
+    pub aggregate_days: u64,
+    pub bucket_secs: u64,
+    pub compaction_interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        RetentionConfig {
+            raw_days: 7,
+            aggregate_days: 180,
+            bucket_secs: 15 * 60,
+            compaction_interval_secs: 3600,
+        }
+    }
+}
+
+/// Durations of the trips of one line that left the fragment within a bucket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    pub from_stop: String,
+    pub to_stop: String,
+    pub route: Option<String>,
+    pub direction: Option<String>,
+    /// Unix time the bucket starts at.
+    pub bucket_start: i64,
+    pub bucket_secs: u64,
+    pub count: u64,
+    pub mean_secs: f64,
+    pub min_secs: u64,
+    pub p10_secs: u64,
+    pub median_secs: u64,
+    pub p90_secs: u64,
+    pub max_secs: u64,
+}
+
+pub(crate) type AggregateKey = (String, String, Option<String>, Option<String>, i64);
+
+impl Aggregate {
+    pub(crate) fn key(&self) -> AggregateKey {
+        (
+            self.from_stop.clone(),
+            self.to_stop.clone(),
+            self.route.clone(),
+            self.direction.clone(),
+            self.bucket_start,
+        )
+    }
+
+    pub fn line(&self) -> Option<Line> {
+        self.route.clone().map(|route| Line {
+            route,
+            direction: self.direction.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Folds another bucket of the same line in. Percentiles become
+    /// count-weighted means of both, so they are only approximate.
+    fn merge(&mut self, other: &Aggregate) {
+        let (count, other_count) = (self.count as f64, other.count as f64);
+        let weigh = |a: f64, b: f64| (a * count + b * other_count) / (count + other_count);
+        let weigh_secs = |a: u64, b: u64| weigh(a as f64, b as f64).round() as u64;
+
+        self.mean_secs = weigh(self.mean_secs, other.mean_secs);
+        self.p10_secs = weigh_secs(self.p10_secs, other.p10_secs);
+        self.median_secs = weigh_secs(self.median_secs, other.median_secs);
+        self.p90_secs = weigh_secs(self.p90_secs, other.p90_secs);
+        self.min_secs = self.min_secs.min(other.min_secs);
+        self.max_secs = self.max_secs.max(other.max_secs);
+        self.count += other.count;
+    }
+}
+
+fn bucket_start(time: i64, bucket_secs: u64) -> i64 {
+    time - time.rem_euclid(bucket_secs as i64)
+}
+
+/// Like `bucket_start`, but aligned to local time so that days start at
+/// midnight.
+fn local_bucket_start(time: i64, bucket_secs: u64) -> i64 {
+    let offset = TIMEZONE.timestamp(time, 0).offset().fix().local_minus_utc() as i64;
+    bucket_start(time + offset, bucket_secs) - offset
+}
+
+fn sort_buckets(buckets: &mut [Aggregate]) {
+    buckets.sort_by(|a, b| (a.bucket_start, &a.route).cmp(&(b.bucket_start, &b.route)));
+}
+
+/// Rolls samples up into buckets of their fragment and line.
+pub fn rollup(samples: &[SampleRecord], bucket_secs: u64) -> Vec<Aggregate> {
+    let mut buckets: BTreeMap<AggregateKey, Vec<u64>> = BTreeMap::new();
+    for sample in samples {
+        buckets
+            .entry((
+                sample.from_stop.clone(),
+                sample.to_stop.clone(),
+                sample.route.clone(),
+                sample.direction.clone(),
+                bucket_start(sample.leave_time, bucket_secs),
+            ))
+            .or_default()
+            .push(sample.duration_secs);
+    }
+
+    buckets
+        .into_iter()
+        .map(
+            |((from_stop, to_stop, route, direction, bucket_start), mut secs)| {
+                secs.sort_unstable();
+                let at = |p| percentile(&secs, p).unwrap_or(0);
+
+                Aggregate {
+                    from_stop,
+                    to_stop,
+                    route,
+                    direction,
+                    bucket_start,
+                    bucket_secs,
+                    count: secs.len() as u64,
+                    mean_secs: secs.iter().sum::<u64>() as f64 / secs.len() as f64,
+                    min_secs: secs[0],
+                    p10_secs: at(0.1),
+                    median_secs: at(0.5),
+                    p90_secs: at(0.9),
+                    max_secs: secs[secs.len() - 1],
+                }
+            },
+        )
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Compaction {
+    pub rolled_up: usize,
+    pub aggregates: usize,
+    pub expired_aggregates: usize,
+}
+
+/// Rolls up samples older than the raw retention, in whole buckets, and
+/// drops rollups older than the aggregate retention. Samples landing in an
+/// already rolled up bucket, e.g. stored late, are merged into its rollup.
+pub fn compact(
+    store: &dyn SampleStore,
+    config: &RetentionConfig,
+    now: Timestamp,
+) -> io::Result<Compaction> {
+    let raw_before = bucket_start(
+        (now - chrono::Duration::days(config.raw_days as i64)).timestamp(),
+        config.bucket_secs,
+    );
+    let raw_before = TIMEZONE.timestamp(raw_before, 0);
+    let aggregates_before = now - chrono::Duration::days(config.aggregate_days as i64);
+
+    let samples = store.samples_before(raw_before)?;
+    let aggregates = merge_stored(store, rollup(&samples, config.bucket_secs))?;
+    let mut compaction = Compaction {
+        rolled_up: samples.len(),
+        aggregates: aggregates.len(),
+        ..Compaction::default()
+    };
+
+    store.save_aggregates(&aggregates)?;
+    store.delete_samples_before(raw_before)?;
+    compaction.expired_aggregates = store.delete_aggregates_before(aggregates_before)?;
+
+    Ok(compaction)
+}
+
+/// Folds rollups already stored for the same buckets into `aggregates`.
+fn merge_stored(store: &dyn SampleStore, aggregates: Vec<Aggregate>) -> io::Result<Vec<Aggregate>> {
+    let mut ranges: BTreeMap<Edge, (i64, i64)> = BTreeMap::new();
+    for a in &aggregates {
+        let end = a.bucket_start + a.bucket_secs as i64;
+        ranges
+            .entry(Edge::new(&a.from_stop, &a.to_stop))
+            .and_modify(|(from, to)| {
+                *from = (*from).min(a.bucket_start);
+                *to = (*to).max(end);
+            })
+            .or_insert((a.bucket_start, end));
+    }
+
+    let mut merged: BTreeMap<_, _> = aggregates.into_iter().map(|a| (a.key(), a)).collect();
+    for (edge, (from, to)) in ranges {
+        let stored = store.aggregates_between(
+            &edge,
+            TIMEZONE.timestamp(from, 0),
+            TIMEZONE.timestamp(to, 0),
+        )?;
+        for old in stored {
+            if let Some(aggregate) = merged.get_mut(&old.key()) {
+                aggregate.merge(&old);
+            }
+        }
+    }
+
+    Ok(merged.into_values().collect())
+}
+
+/// Buckets of the fragment that left within `[from, to)`, rolled up on the
+/// fly from samples still kept and read from rollups before that.
+pub fn history(
+    store: &dyn SampleStore,
+    edge: &Edge,
+    from: Timestamp,
+    to: Timestamp,
+    filter: &LineFilter,
+    bucket_secs: u64,
+) -> io::Result<Vec<Aggregate>> {
+    let mut buckets: BTreeMap<_, _> = store
+        .aggregates_between(edge, from, to)?
+        .into_iter()
+        .map(|a| (a.key(), a))
+        .collect();
+    let samples = store.samples_between(edge, Some(from), to)?;
+    for aggregate in rollup(&samples, bucket_secs) {
+        match buckets.entry(aggregate.key()) {
+            Entry::Vacant(entry) => {
+                entry.insert(aggregate);
+            }
+            Entry::Occupied(mut entry) => entry.get_mut().merge(&aggregate),
+        }
+    }
+
+    let mut buckets: Vec<_> = buckets
+        .into_values()
+        .filter(|a| filter.matches(a.line().as_ref()))
+        .collect();
+    sort_buckets(&mut buckets);
+
+    Ok(buckets)
+}
+
+/// Merges buckets into coarser ones of `bucket_secs`, which should be a
+/// multiple of their size.
+pub fn resample(buckets: Vec<Aggregate>, bucket_secs: u64) -> Vec<Aggregate> {
+    let mut merged = BTreeMap::new();
+    for bucket in buckets {
+        let start = local_bucket_start(bucket.bucket_start, bucket_secs);
+        let key = (
+            bucket.from_stop.clone(),
+            bucket.to_stop.clone(),
+            bucket.route.clone(),
+            bucket.direction.clone(),
+            start,
+        );
+        match merged.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(Aggregate {
+                    bucket_start: start,
+                    bucket_secs,
+                    ..bucket
+                });
+            }
+            Entry::Occupied(mut entry) => entry.get_mut().merge(&bucket),
+        }
+    }
+
+    let mut buckets: Vec<_> = merged.into_values().collect();
+    sort_buckets(&mut buckets);
+    buckets
+}
+
+/// Compacts the store in the background. Compaction itself runs on a
+/// thread of its own, so that store I/O does not hold up other actors.
+pub struct Compactor {
+    store: SharedStore,
+    config: RetentionConfig,
+    clock: SharedClock,
+    worker: Option<Addr<CompactionWorker>>,
+}
+
+impl Compactor {
+    pub fn new(store: SharedStore, config: RetentionConfig, clock: SharedClock) -> Compactor {
+        Compactor {
+            store,
+            config,
+            clock,
+            worker: None,
+        }
+    }
+
+    fn compact(&self) {
+        if let Some(worker) = &self.worker {
+            worker.do_send(Compact);
+        }
+    }
+}
+
+impl Actor for Compactor {
+    type Context = Context<Compactor>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let (store, config, clock) = (self.store.clone(), self.config.clone(), self.clock.clone());
+        self.worker = Some(SyncArbiter::start(1, move || CompactionWorker {
+            store: store.clone(),
+            config: config.clone(),
+            clock: clock.clone(),
+        }));
+
+        self.compact();
+        ctx.run_interval(
+            Duration::from_secs(self.config.compaction_interval_secs),
+            |compactor, _| compactor.compact(),
+        );
+    }
+}
+
+struct CompactionWorker {
+    store: SharedStore,
+    config: RetentionConfig,
+    clock: SharedClock,
+}
+
+impl Actor for CompactionWorker {
+    type Context = SyncContext<CompactionWorker>;
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Compact;
+
+impl Handler<Compact> for CompactionWorker {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Compact, _ctx: &mut SyncContext<Self>) {
+        match compact(&*self.store, &self.config, self.clock.now()) {
+            Ok(compaction) => {
+                if compaction != Compaction::default() {
+                    println!(
+                        "Rolled up {} samples into {} buckets, expired {} buckets",
+                        compaction.rolled_up, compaction.aggregates, compaction.expired_aggregates
+                    );
+                }
+            }
+            Err(e) => println!("Compaction failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::tests::record;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn old_samples_are_rolled_up_and_still_queried() {
+        let store = MemoryStore::default();
+        for (trip, leave_secs, duration_secs) in &[("1", 60, 60), ("2", 120, 90), ("3", 1000, 80)] {
+            store
+                .save_sample(&record(trip, *leave_secs, *duration_secs))
+                .unwrap();
+        }
+        let edge = Edge::new("A", "B");
+        let start = record("1", 0, 0).to_sample().leave_time;
+        let config = RetentionConfig {
+            raw_days: 1,
+            ..RetentionConfig::default()
+        };
+
+        let now = start + chrono::Duration::days(1) + chrono::Duration::seconds(900);
+        let compaction = compact(&store, &config, now).unwrap();
+        assert_eq!(compaction.rolled_up, 2);
+        assert_eq!(compaction.aggregates, 1);
+        let later = start + chrono::Duration::days(1);
+        assert_eq!(store.samples_between(&edge, None, later).unwrap().len(), 1);
+
+        let buckets = history(
+            &store,
+            &edge,
+            start,
+            later,
+            &LineFilter::default(),
+            config.bucket_secs,
+        )
+        .unwrap();
+        let summary: Vec<_> = buckets
+            .iter()
+            .map(|b| (b.bucket_start - start.timestamp(), b.count, b.median_secs))
+            .collect();
+        assert_eq!(summary, vec![(0, 2, 60), (900, 1, 80)]);
+
+        let hourly = resample(buckets, 3600);
+        assert_eq!(hourly.len(), 1);
+        assert_eq!(hourly[0].bucket_start, start.timestamp());
+        assert_eq!(
+            (hourly[0].count, hourly[0].min_secs, hourly[0].max_secs),
+            (3, 60, 90)
+        );
+        assert!((hourly[0].mean_secs - 230.0 / 3.0).abs() < 1e-9);
+
+        let now = start + chrono::Duration::days(200);
+        let compaction = compact(&store, &config, now).unwrap();
+        assert_eq!(compaction.rolled_up, 1);
+        assert_eq!(compaction.expired_aggregates, 2);
+    }
+
+    #[test]
+    fn late_samples_are_merged_into_rollups() {
+        let store = MemoryStore::default();
+        let edge = Edge::new("A", "B");
+        let start = record("1", 0, 0).to_sample().leave_time;
+        let config = RetentionConfig {
+            raw_days: 1,
+            ..RetentionConfig::default()
+        };
+        let now = start + chrono::Duration::days(1) + chrono::Duration::seconds(900);
+
+        store.save_sample(&record("1", 60, 60)).unwrap();
+        store.save_sample(&record("2", 120, 90)).unwrap();
+        compact(&store, &config, now).unwrap();
+        store.save_sample(&record("3", 180, 120)).unwrap();
+
+        // Before compaction, the late sample is rolled up on the fly.
+        let history = |store: &MemoryStore| {
+            history(
+                store,
+                &edge,
+                start,
+                now,
+                &LineFilter::default(),
+                config.bucket_secs,
+            )
+            .unwrap()
+        };
+        let buckets = history(&store);
+        assert_eq!(buckets.len(), 1);
+        assert_eq!((buckets[0].count, buckets[0].max_secs), (3, 120));
+
+        let compaction = compact(&store, &config, now).unwrap();
+        assert_eq!(compaction.rolled_up, 1);
+        assert_eq!(history(&store), buckets);
+        let stored = store.aggregates_between(&edge, start, now).unwrap();
+        assert_eq!(
+            (stored[0].count, stored[0].min_secs, stored[0].max_secs),
+            (3, 60, 120)
+        );
+        assert!((stored[0].mean_secs - 90.0).abs() < 1e-9);
+    }
+}
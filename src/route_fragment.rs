@@ -1,34 +1,229 @@
 use actix::prelude::*;
 
-use chrono::NaiveTime;
+use chrono::NaiveDate;
 use log::warn;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::time::Duration;
-use std::time::Instant;
+
+use crate::clock::SharedClock;
+use crate::history::{HistoryConfig, WindowStats};
+use crate::storage::{SampleRecord, SharedStore};
+use crate::timestamp::{self, Timestamp, TripKey};
+
+/// Observations of one departure further apart than this disagree.
+const CONFLICT_TOLERANCE_SECS: i64 = 60;
+
+/// Paired trips are remembered this long, so that departures which are
+/// still reported afterwards are not taken for new trips.
+const PAIRED_MEMORY_SECS: i64 = 3600;
+
+/// Departures still unpaired this long after they happened are given up on,
+/// e.g. trams that short-turned or were missed by polling.
+const ORPHAN_TTL_SECS: i64 = 1800;
 
 pub struct RouteFragment {
-    id: String,
+    edge: Edge,
+    clock: SharedClock,
     stop_names: [String; 2],
-    past_trip_duration: Vec<Duration>,
-    last_update_time: Option<NaiveTime>,
-    current_trip_starts: HashMap<String, NaiveTime>,
-    current_trip_stops: HashMap<String, NaiveTime>,
+    history: HistoryConfig,
+    /// Oldest first, bounded by the history config.
+    samples: VecDeque<Sample>,
+    next_sample_seq: u64,
+    store: Option<SharedStore>,
+    current_trip_starts: HashMap<(Source, TripKey), Observation>,
+    current_trip_stops: HashMap<(Source, TripKey), Observation>,
+    paired_trips: HashMap<(Source, TripKey), PairedTrip>,
+    duplicates: u64,
+    conflicts: u64,
+    orphans: OrphanCounts,
 }
 
 impl Actor for RouteFragment {
     type Context = Context<RouteFragment>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.load_samples();
+        ctx.run_interval(Duration::from_secs(60), |fragment, _| {
+            fragment.expire_orphans()
+        });
+    }
 }
 
 impl RouteFragment {
-    pub fn new(id: String) -> RouteFragment {
+    pub fn new(edge: Edge, clock: SharedClock, history: HistoryConfig) -> RouteFragment {
         RouteFragment {
-            id: id,
+            edge,
+            clock,
             stop_names: ["?".to_string(), "?".to_string()],
-            last_update_time: None,
-            past_trip_duration: Vec::new(),
+            history,
+            samples: VecDeque::new(),
+            next_sample_seq: 0,
+            store: None,
             current_trip_starts: HashMap::new(),
             current_trip_stops: HashMap::new(),
+            paired_trips: HashMap::new(),
+            duplicates: 0,
+            conflicts: 0,
+            orphans: OrphanCounts::default(),
+        }
+    }
+
+    /// Stores finished samples, and starts from those stored before.
+    pub fn with_store(mut self, store: Option<SharedStore>) -> RouteFragment {
+        self.store = store;
+        self
+    }
+}
+
+/// Ordered pair of stops a trip was seen departing one after another.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+}
+
+impl Edge {
+    pub fn new(from: &str, to: &str) -> Edge {
+        Edge {
+            from: String::from(from),
+            to: String::from(to),
+        }
+    }
+}
+
+impl std::str::FromStr for Edge {
+    type Err = ();
+
+    /// Parses the `from-to` form used in fragment URLs.
+    fn from_str(s: &str) -> Result<Edge, ()> {
+        let mut stops = s.splitn(2, '-');
+        match (stops.next(), stops.next()) {
+            (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => Ok(Edge::new(from, to)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.from, self.to)
+    }
+}
+
+/// Trips dropped from the fragment without being paired.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct OrphanCounts {
+    /// Entered the fragment, but never reached its end.
+    pub never_left: u64,
+    /// Reached the end of the fragment, but were never seen entering it.
+    pub never_entered: u64,
+}
+
+/// Line a trip runs on, as shown on the tram.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Line {
+    /// Route short name, e.g. `4`.
+    pub route: String,
+    pub direction: String,
+}
+
+/// Selects samples of one line or direction, unset fields match anything.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct LineFilter {
+    pub line: Option<String>,
+    pub direction: Option<String>,
+}
+
+impl LineFilter {
+    pub(crate) fn matches(&self, line: Option<&Line>) -> bool {
+        let route = line.map(|l| l.route.as_str());
+        let direction = line.map(|l| l.direction.as_str());
+
+        (self.line.is_none() || self.line.as_deref() == route)
+            && (self.direction.is_none() || self.direction.as_deref() == direction)
+    }
+}
+
+/// One trip measured travelling the fragment.
+#[derive(Serialize, Debug, Clone)]
+pub struct Sample {
+    #[serde(skip)]
+    pub(crate) seq: u64,
+    pub trip_id: String,
+    pub vehicle_id: Option<String>,
+    pub line: Option<Line>,
+    /// Departure from the first stop.
+    #[serde(serialize_with = "timestamp::serialize")]
+    pub entry_time: Timestamp,
+    /// Departure from the second stop.
+    #[serde(serialize_with = "timestamp::serialize")]
+    pub leave_time: Timestamp,
+    #[serde(rename = "duration_secs", serialize_with = "serialize_secs")]
+    pub duration: Duration,
+    pub source: Source,
+    /// How far off the duration may be due to the resolution of reported times.
+    pub uncertainty_secs: u64,
+}
+
+fn serialize_secs<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+/// One departure, as estimated by the freshest report of it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Observation {
+    passage: String,
+    #[serde(
+        serialize_with = "timestamp::serialize",
+        deserialize_with = "timestamp::deserialize"
+    )]
+    time: Timestamp,
+    age_secs: u64,
+    line: Option<Line>,
+    vehicle_id: Option<String>,
+}
+
+impl Observation {
+    /// Keeps the fresher of two reports of the same departure. Returns
+    /// whether they disagree, in which case the report is not trusted.
+    fn merge(&mut self, seen: Observation) -> bool {
+        let conflict = self.passage != seen.passage
+            || (seen.time - self.time).num_seconds().abs() > CONFLICT_TOLERANCE_SECS;
+        if !conflict && seen.age_secs < self.age_secs {
+            *self = seen;
+        }
+        conflict
+    }
+}
+
+struct PairedTrip {
+    start: Observation,
+    stop: Observation,
+    /// Sequence number of the sample, if the duration was valid.
+    sample: Option<u64>,
+}
+
+/// Where an event was observed; entry and leave are only paired within the
+/// same source.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Stop,
+    Trip,
+}
+
+impl Source {
+    /// Resolution of the departure times reported by the source: stop
+    /// passages count seconds, trip passages show clock minutes.
+    fn resolution_secs(self) -> u64 {
+        match self {
+            Source::Stop => 1,
+            Source::Trip => 60,
         }
     }
 }
@@ -36,17 +231,27 @@ impl RouteFragment {
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct FragmentEntryEvent {
-    pub trip_id: String,
-    pub instant: Instant,
-    pub time: NaiveTime,
+    pub trip: TripKey,
+    pub source: Source,
+    /// Identifies the departure, repeated reports of it carry the same one.
+    pub passage: String,
+    /// How long after the departure it was observed.
+    pub age_secs: u64,
+    pub line: Option<Line>,
+    pub vehicle_id: Option<String>,
+    pub time: Timestamp,
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct FragmentLeaveEvent {
-    pub trip_id: String,
-    pub instant: Instant,
-    pub time: NaiveTime,
+    pub trip: TripKey,
+    pub source: Source,
+    pub passage: String,
+    pub age_secs: u64,
+    pub line: Option<Line>,
+    pub vehicle_id: Option<String>,
+    pub time: Timestamp,
 }
 
 #[derive(Message, Debug)]
@@ -60,9 +265,17 @@ pub enum UpdateMeta {
 pub struct RouteFragmentStats {
     pub stop_id: String,
     pub stop_name: String,
+    pub next_stop_id: String,
+    pub next_stop_name: String,
     pub time: Option<u64>,
     pub update_secs: Option<u64>,
     pub active_trips: Vec<String>,
+    /// Repeated reports of already known departures.
+    pub duplicates: u64,
+    /// Repeated reports disagreeing with the known departure.
+    pub conflicts: u64,
+    pub orphans: OrphanCounts,
+    pub windows: Vec<WindowStats>,
 }
 
 #[derive(Message, Debug)]
@@ -73,33 +286,494 @@ impl Handler<FragmentStatusRequest> for RouteFragment {
     type Result = RouteFragmentStats;
 
     fn handle(&mut self, _msg: FragmentStatusRequest, _ctx: &mut Context<Self>) -> Self::Result {
-        let last_update = self.last_update_time.map(|x| {
-            (chrono::Local::now().time() - x)
-                .to_std()
-                .unwrap()
-                .as_secs()
-        });
-        return RouteFragmentStats {
-            stop_id: self.id.clone(),
+        self.stats(&LineFilter::default())
+    }
+}
+
+/// Stats of the samples selected by the filter only.
+#[derive(Message, Debug)]
+#[rtype(result = "RouteFragmentStats")]
+pub struct FilteredStatusRequest(pub LineFilter);
+
+impl Handler<FilteredStatusRequest> for RouteFragment {
+    type Result = RouteFragmentStats;
+
+    fn handle(&mut self, msg: FilteredStatusRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        self.stats(&msg.0)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct LineStats {
+    pub line: Option<Line>,
+    pub samples: usize,
+    pub time: Option<u64>,
+    pub mean_secs: Option<u64>,
+    pub update_secs: Option<u64>,
+}
+
+/// Stats of every line seen on the fragment, separately.
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<LineStats>")]
+pub struct LineStatsRequest;
+
+impl Handler<LineStatsRequest> for RouteFragment {
+    type Result = MessageResult<LineStatsRequest>;
+
+    fn handle(&mut self, _msg: LineStatsRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = self.clock.now();
+        let mut lines: HashMap<Option<&Line>, Vec<&Sample>> = HashMap::new();
+        for sample in &self.samples {
+            lines.entry(sample.line.as_ref()).or_default().push(sample);
+        }
+
+        let mut stats: Vec<_> = lines
+            .into_iter()
+            .map(|(line, samples)| {
+                let total: Duration = samples.iter().map(|s| s.duration).sum();
+                LineStats {
+                    line: line.cloned(),
+                    samples: samples.len(),
+                    time: samples.last().map(|s| s.duration.as_secs()),
+                    mean_secs: Some(total.as_secs() / samples.len() as u64),
+                    update_secs: samples.last().map(|s| secs_since(now, s.leave_time)),
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| a.line.cmp(&b.line));
+
+        MessageResult(stats)
+    }
+}
+
+/// Position right after a sample in leave order. Samples often share their
+/// leave time, so ties are broken by sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SampleCursor {
+    leave_time: i64,
+    seq: u64,
+}
+
+impl SampleCursor {
+    fn of(sample: &Sample) -> SampleCursor {
+        SampleCursor {
+            leave_time: sample.leave_time.timestamp(),
+            seq: sample.seq,
+        }
+    }
+}
+
+impl std::str::FromStr for SampleCursor {
+    type Err = ();
+
+    /// Parses the `{leave time}-{seq}` form handed out to clients.
+    fn from_str(s: &str) -> Result<SampleCursor, ()> {
+        let mut parts = s.splitn(2, '-');
+        match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+            (Some(Ok(leave_time)), Some(Ok(seq))) => Ok(SampleCursor { leave_time, seq }),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for SampleCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.leave_time, self.seq)
+    }
+}
+
+impl Serialize for SampleCursor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Samples which left the fragment within `[from, to)` and after the cursor,
+/// oldest first.
+#[derive(Message, Debug)]
+#[rtype(result = "SamplePage")]
+pub struct SamplesRequest {
+    pub from: Option<Timestamp>,
+    pub to: Option<Timestamp>,
+    pub after: Option<SampleCursor>,
+    pub limit: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SamplePage {
+    pub samples: Vec<Sample>,
+    /// Where the next page starts, if there are more samples in range.
+    pub next: Option<SampleCursor>,
+}
+
+impl Handler<SamplesRequest> for RouteFragment {
+    type Result = MessageResult<SamplesRequest>;
+
+    fn handle(&mut self, msg: SamplesRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut samples: Vec<_> = self
+            .samples
+            .iter()
+            .filter(|s| !matches!(msg.from, Some(from) if s.leave_time < from))
+            .filter(|s| !matches!(msg.to, Some(to) if s.leave_time >= to))
+            .filter(|s| !matches!(msg.after, Some(after) if SampleCursor::of(s) <= after))
+            .collect();
+        samples.sort_by_key(|s| SampleCursor::of(s));
+
+        let more = samples.len() > msg.limit;
+        samples.truncate(msg.limit);
+        let next = samples.last().filter(|_| more).map(|s| SampleCursor::of(s));
+
+        MessageResult(SamplePage {
+            samples: samples.into_iter().cloned().collect(),
+            next,
+        })
+    }
+}
+
+/// Trips the fragment has not paired yet, or is still hearing about, so that
+/// a restart does not lose them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FragmentSnapshot {
+    pub edge: Edge,
+    starts: Vec<PendingTrip>,
+    stops: Vec<PendingTrip>,
+    paired: Vec<PendingPair>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingTrip {
+    source: Source,
+    trip: TripKey,
+    observation: Observation,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingPair {
+    source: Source,
+    trip: TripKey,
+    start: Observation,
+    stop: Observation,
+}
+
+impl FragmentSnapshot {
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty() && self.stops.is_empty() && self.paired.is_empty()
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "FragmentSnapshot")]
+pub struct TakeSnapshot;
+
+impl Handler<TakeSnapshot> for RouteFragment {
+    type Result = MessageResult<TakeSnapshot>;
+
+    fn handle(&mut self, _msg: TakeSnapshot, _ctx: &mut Context<Self>) -> Self::Result {
+        let pending = |trips: &HashMap<(Source, TripKey), Observation>| {
+            trips
+                .iter()
+                .map(|((source, trip), observation)| PendingTrip {
+                    source: *source,
+                    trip: trip.clone(),
+                    observation: observation.clone(),
+                })
+                .collect()
+        };
+
+        MessageResult(FragmentSnapshot {
+            edge: self.edge.clone(),
+            starts: pending(&self.current_trip_starts),
+            stops: pending(&self.current_trip_stops),
+            paired: self
+                .paired_trips
+                .iter()
+                .map(|((source, trip), paired)| PendingPair {
+                    source: *source,
+                    trip: trip.clone(),
+                    start: paired.start.clone(),
+                    stop: paired.stop.clone(),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Brings back trips of a snapshot, except those last seen before `since`.
+/// Trips reported since the start win over their snapshot.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RestoreSnapshot {
+    pub snapshot: FragmentSnapshot,
+    pub since: Timestamp,
+}
+
+impl Handler<RestoreSnapshot> for RouteFragment {
+    type Result = ();
+
+    fn handle(&mut self, msg: RestoreSnapshot, _ctx: &mut Context<Self>) {
+        let since = msg.since;
+        let mut restored = 0;
+
+        for pending in msg.snapshot.starts {
+            if pending.observation.time > since {
+                self.current_trip_starts
+                    .entry((pending.source, pending.trip))
+                    .or_insert(pending.observation);
+                restored += 1;
+            }
+        }
+        for pending in msg.snapshot.stops {
+            if pending.observation.time > since {
+                self.current_trip_stops
+                    .entry((pending.source, pending.trip))
+                    .or_insert(pending.observation);
+                restored += 1;
+            }
+        }
+        for pending in msg.snapshot.paired {
+            if pending.stop.time <= since {
+                continue;
+            }
+            // Samples come back from the store with new sequence numbers.
+            let sample = self
+                .samples
+                .iter()
+                .rev()
+                .find(|s| {
+                    s.source == pending.source
+                        && s.trip_id == pending.trip.trip_id
+                        && s.leave_time == pending.stop.time
+                })
+                .map(|s| s.seq);
+            self.paired_trips
+                .entry((pending.source, pending.trip))
+                .or_insert(PairedTrip {
+                    start: pending.start,
+                    stop: pending.stop,
+                    sample,
+                });
+            restored += 1;
+        }
+
+        println!("Restored {} trips of fragment {}", restored, self.edge);
+    }
+}
+
+fn secs_since(now: Timestamp, time: Timestamp) -> u64 {
+    (now - time).to_std().map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl RouteFragment {
+    fn stats(&mut self, filter: &LineFilter) -> RouteFragmentStats {
+        self.expire_orphans();
+        self.expire_samples();
+
+        let now = self.clock.now();
+        let last = self
+            .samples
+            .iter()
+            .rev()
+            .find(|s| filter.matches(s.line.as_ref()));
+        let mut active_trips: Vec<_> = self
+            .current_trip_starts
+            .iter()
+            .filter(|(_, start)| filter.matches(start.line.as_ref()))
+            .map(|((_, trip), _)| trip.trip_id.clone())
+            .collect();
+        active_trips.sort();
+        active_trips.dedup();
+
+        let mut selected: Vec<_> = self
+            .samples
+            .iter()
+            .filter(|s| filter.matches(s.line.as_ref()))
+            .collect();
+        selected.sort_by_key(|s| s.leave_time);
+        let windows = self
+            .history
+            .windows
+            .iter()
+            .map(|&window| {
+                let start = window.start(now);
+                let durations: Vec<_> = selected
+                    .iter()
+                    .filter(|s| s.leave_time >= start)
+                    .map(|s| s.duration)
+                    .collect();
+                WindowStats::new(window, &durations, self.history.ewma_alpha)
+            })
+            .collect();
+
+        RouteFragmentStats {
+            stop_id: self.edge.from.clone(),
             stop_name: self.stop_names[0].clone(),
-            time: self.past_trip_duration.last().map(|x| x.as_secs()),
-            update_secs: last_update,
-            active_trips: self.current_trip_starts.keys().cloned().collect(),
+            next_stop_id: self.edge.to.clone(),
+            next_stop_name: self.stop_names[1].clone(),
+            time: last.map(|x| x.duration.as_secs()),
+            update_secs: last.map(|x| secs_since(now, x.leave_time)),
+            active_trips,
+            duplicates: self.duplicates,
+            conflicts: self.conflicts,
+            orphans: self.orphans,
+            windows,
+        }
+    }
+
+    fn expire_samples(&mut self) {
+        let retain_after =
+            self.clock.now() - chrono::Duration::seconds(self.history.retention_secs as i64);
+        self.samples.retain(|s| s.leave_time > retain_after);
+
+        let excess = self.samples.len().saturating_sub(self.history.max_samples);
+        self.samples.drain(..excess);
+    }
+
+    fn load_samples(&mut self) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
         };
+        let since =
+            self.clock.now() - chrono::Duration::seconds(self.history.retention_secs as i64);
+
+        match store.load_samples(&self.edge, since, self.history.max_samples) {
+            Ok(samples) => {
+                println!("Loaded {} samples of fragment {}", samples.len(), self.edge);
+                for mut sample in samples {
+                    sample.seq = self.next_sample_seq;
+                    self.next_sample_seq += 1;
+                    self.samples.push_back(sample);
+                }
+            }
+            Err(e) => println!("Failed to load samples of fragment {}: {}", self.edge, e),
+        }
+    }
+
+    fn store_sample(&self, service_date: NaiveDate, seq: Option<u64>) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+        if let Some(sample) = self.samples.iter().rev().find(|s| Some(s.seq) == seq) {
+            let record = SampleRecord::new(&self.edge, service_date, sample);
+            if let Err(e) = store.save_sample(&record) {
+                println!("Failed to store sample of trip {}: {}", sample.trip_id, e);
+            }
+        }
     }
 }
 
 impl RouteFragment {
-    fn insert_finished_trip(&mut self, start_time: &NaiveTime, stop_time: &NaiveTime) {
-        match (*stop_time - *start_time).to_std() {
-            Ok(duration) => {
-                self.past_trip_duration.push(duration);
-                self.last_update_time = Some(*stop_time);
+    fn duration(&self, start: &Observation, stop: &Observation) -> Option<Duration> {
+        match (stop.time - start.time).to_std() {
+            Ok(duration) => Some(duration),
+            Err(_) => {
+                warn!(
+                    "Zero or negative trip duration for route fragment {}",
+                    self.stop_names[0]
+                );
+                None
             }
-            Err(_) => warn!(
-                "Zero or negative trip duration for route fragment {}",
-                self.stop_names[0]
-            ),
+        }
+    }
+
+    fn insert_finished_trip(
+        &mut self,
+        key: (Source, TripKey),
+        start: Observation,
+        stop: Observation,
+    ) {
+        let sample = self.duration(&start, &stop).map(|duration| {
+            let seq = self.next_sample_seq;
+            self.next_sample_seq += 1;
+            self.samples.push_back(Sample {
+                seq,
+                trip_id: key.1.trip_id.clone(),
+                vehicle_id: start.vehicle_id.clone().or_else(|| stop.vehicle_id.clone()),
+                line: start.line.clone().or_else(|| stop.line.clone()),
+                entry_time: start.time,
+                leave_time: stop.time,
+                duration,
+                source: key.0,
+                uncertainty_secs: 2 * key.0.resolution_secs(),
+            });
+            seq
+        });
+        self.store_sample(key.1.service_date, sample);
+        self.expire_samples();
+
+        let forget_before = stop.time - chrono::Duration::seconds(PAIRED_MEMORY_SECS);
+        self.paired_trips
+            .retain(|_, paired| paired.stop.time > forget_before);
+        self.paired_trips.insert(
+            key,
+            PairedTrip {
+                start,
+                stop,
+                sample,
+            },
+        );
+    }
+
+    /// Folds a report of an already paired trip into its measured duration.
+    fn update_paired_trip(&mut self, key: &(Source, TripKey), seen: Observation, is_start: bool) {
+        let paired = match self.paired_trips.get_mut(key) {
+            Some(paired) => paired,
+            None => return,
+        };
+        let conflict = if is_start {
+            paired.start.merge(seen)
+        } else {
+            paired.stop.merge(seen)
+        };
+        let (start, stop, sample) = (paired.start.clone(), paired.stop.clone(), paired.sample);
+        self.count_repeated(conflict);
+
+        let duration = self.duration(&start, &stop);
+        let seq = sample;
+        let sample = self.samples.iter_mut().rev().find(|s| Some(s.seq) == seq);
+        if let (Some(sample), Some(duration)) = (sample, duration) {
+            let changed = sample.duration != duration
+                || sample.entry_time != start.time
+                || sample.leave_time != stop.time;
+            if !changed {
+                return;
+            }
+            sample.duration = duration;
+            sample.entry_time = start.time;
+            sample.leave_time = stop.time;
+            self.store_sample(key.1.service_date, seq);
+        }
+    }
+
+    fn expire_orphans(&mut self) {
+        let expire_before = self.clock.now() - chrono::Duration::seconds(ORPHAN_TTL_SECS);
+
+        let starts = self.current_trip_starts.len();
+        self.current_trip_starts
+            .retain(|_, start| start.time > expire_before);
+        let never_left = starts - self.current_trip_starts.len();
+
+        let stops = self.current_trip_stops.len();
+        self.current_trip_stops
+            .retain(|_, stop| stop.time > expire_before);
+        let never_entered = stops - self.current_trip_stops.len();
+
+        if never_left + never_entered > 0 {
+            println!(
+                "Expired {} trips that never left and {} that never entered fragment {}",
+                never_left, never_entered, self.stop_names[0]
+            );
+            self.orphans.never_left += never_left as u64;
+            self.orphans.never_entered += never_entered as u64;
+        }
+    }
+
+    fn count_repeated(&mut self, conflict: bool) {
+        if conflict {
+            self.conflicts += 1;
+        } else {
+            self.duplicates += 1;
         }
     }
 }
@@ -108,22 +782,36 @@ impl Handler<FragmentEntryEvent> for RouteFragment {
     type Result = ();
 
     fn handle(&mut self, msg: FragmentEntryEvent, _ctx: &mut Context<Self>) {
-        if let Some(stop) = self.current_trip_stops.get(&msg.trip_id).cloned() {
-            self.insert_finished_trip(&msg.time, &stop);
-            self.current_trip_stops.remove(&msg.trip_id);
+        let key = (msg.source, msg.trip);
+        let seen = Observation {
+            passage: msg.passage,
+            time: msg.time,
+            age_secs: msg.age_secs,
+            line: msg.line,
+            vehicle_id: msg.vehicle_id,
+        };
+
+        if self.paired_trips.contains_key(&key) {
+            self.update_paired_trip(&key, seen, true);
+        } else if let Some(start) = self.current_trip_starts.get_mut(&key) {
+            let conflict = start.merge(seen);
+            self.count_repeated(conflict);
+        } else if let Some(stop) = self.current_trip_stops.remove(&key) {
+            let trip = key.1.clone();
+            self.insert_finished_trip(key, seen, stop);
             println!(
                 "Unregistered trip (rev-order) {} for fragment {}-{}, took: {:?}",
-                msg.trip_id,
+                trip,
                 self.stop_names[0],
                 self.stop_names[1],
-                self.past_trip_duration.last().map(|x| x.as_secs())
+                self.samples.back().map(|x| x.duration.as_secs())
             )
         } else {
             println!(
                 "Registered new trip {} for fragment {}",
-                &msg.trip_id, &self.stop_names[0]
+                &key.1, &self.stop_names[0]
             );
-            self.current_trip_starts.insert(msg.trip_id, msg.time);
+            self.current_trip_starts.insert(key, seen);
         }
     }
 }
@@ -132,18 +820,72 @@ impl Handler<FragmentLeaveEvent> for RouteFragment {
     type Result = ();
 
     fn handle(&mut self, msg: FragmentLeaveEvent, _ctx: &mut Context<Self>) {
-        if let Some(start) = self.current_trip_starts.get(&msg.trip_id).cloned() {
-            self.insert_finished_trip(&start, &msg.time);
-            self.current_trip_starts.remove(&msg.trip_id);
+        let key = (msg.source, msg.trip);
+        let seen = Observation {
+            passage: msg.passage,
+            time: msg.time,
+            age_secs: msg.age_secs,
+            line: msg.line,
+            vehicle_id: msg.vehicle_id,
+        };
+
+        if self.paired_trips.contains_key(&key) {
+            self.update_paired_trip(&key, seen, false);
+        } else if let Some(stop) = self.current_trip_stops.get_mut(&key) {
+            let conflict = stop.merge(seen);
+            self.count_repeated(conflict);
+        } else if let Some(start) = self.current_trip_starts.remove(&key) {
+            let trip = key.1.clone();
+            self.insert_finished_trip(key, start, seen);
             println!(
                 "Unregistered trip {} for fragment {}-{}, took: {:?}",
-                msg.trip_id,
+                trip,
                 self.stop_names[0],
                 self.stop_names[1],
-                self.past_trip_duration.last().map(|x| x.as_secs())
+                self.samples.back().map(|x| x.duration.as_secs())
             )
         } else {
-            self.current_trip_stops.insert(msg.trip_id, msg.time);
+            self.current_trip_stops.insert(key, seen);
+        }
+    }
+}
+
+/// Takes back a trip paired across a stop it turned out to depart in
+/// between, stopping the fragment too when it is `retire`d.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct WithdrawTrip {
+    pub trip: TripKey,
+    pub source: Source,
+    pub retire: bool,
+}
+
+impl Handler<WithdrawTrip> for RouteFragment {
+    type Result = ();
+
+    fn handle(&mut self, msg: WithdrawTrip, ctx: &mut Context<Self>) {
+        let key = (msg.source, msg.trip);
+        self.current_trip_starts.remove(&key);
+        self.current_trip_stops.remove(&key);
+
+        let seq = self
+            .paired_trips
+            .remove(&key)
+            .and_then(|paired| paired.sample);
+        if let Some(i) = self.samples.iter().position(|s| Some(s.seq) == seq) {
+            let sample = self.samples.remove(i).unwrap();
+            println!("Withdrew trip {} from fragment {}", key.1, self.edge);
+
+            if let Some(store) = &self.store {
+                let record = SampleRecord::new(&self.edge, key.1.service_date, &sample);
+                if let Err(e) = store.delete_sample(&record) {
+                    println!("Failed to delete sample of trip {}: {}", sample.trip_id, e);
+                }
+            }
+        }
+
+        if msg.retire {
+            ctx.stop();
         }
     }
 }
@@ -168,3 +910,358 @@ impl RouteFragment {
         UpdateMeta::UpdateStopName(s.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::clock::SimulatedClock;
+    use crate::retention::Aggregate;
+    use crate::storage::{MemoryStore, SampleStore};
+    use crate::timestamp::TIMEZONE;
+    use crate::trajectory::Trajectory;
+
+    fn at(secs: i64, age_secs: u64) -> (Timestamp, u64) {
+        let time = TIMEZONE.ymd(2020, 3, 16).and_hms(12, 0, 0) + chrono::Duration::seconds(secs);
+        (time, age_secs)
+    }
+
+    fn entry((time, age_secs): (Timestamp, u64)) -> FragmentEntryEvent {
+        FragmentEntryEvent {
+            trip: TripKey::new("1", &time),
+            source: Source::Stop,
+            passage: String::from("-11"),
+            age_secs,
+            line: None,
+            vehicle_id: None,
+            time,
+        }
+    }
+
+    fn leave((time, age_secs): (Timestamp, u64)) -> FragmentLeaveEvent {
+        FragmentLeaveEvent {
+            trip: TripKey::new("1", &time),
+            source: Source::Stop,
+            passage: String::from("-12"),
+            age_secs,
+            line: None,
+            vehicle_id: None,
+            time,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn repeated_reports_keep_freshest_estimate() {
+        let fragment = RouteFragment::new(
+            Edge::new("1", "2"),
+            Arc::new(SimulatedClock::new(at(0, 0).0)),
+            HistoryConfig::default(),
+        )
+        .start();
+
+        fragment.send(entry(at(2, 30))).await.unwrap();
+        fragment.send(entry(at(0, 5))).await.unwrap();
+        fragment.send(leave(at(90, 10))).await.unwrap();
+        fragment.send(leave(at(93, 40))).await.unwrap();
+        fragment.send(entry(at(4, 50))).await.unwrap();
+
+        let stats = fragment.send(FragmentStatusRequest).await.unwrap();
+        assert_eq!(stats.time, Some(90));
+        assert_eq!(stats.duplicates, 3);
+        assert_eq!(stats.conflicts, 0);
+        assert!(stats.active_trips.is_empty());
+
+        fragment.send(leave(at(85, 2))).await.unwrap();
+        fragment.send(leave(at(300, 1))).await.unwrap();
+
+        let stats = fragment.send(FragmentStatusRequest).await.unwrap();
+        assert_eq!(stats.time, Some(85));
+        assert_eq!(stats.duplicates, 4);
+        assert_eq!(stats.conflicts, 1);
+    }
+
+    #[actix_rt::test]
+    async fn stored_samples_survive_restart() {
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        let fragment = |store| {
+            RouteFragment::new(
+                Edge::new("1", "2"),
+                Arc::new(SimulatedClock::new(at(0, 0).0)),
+                HistoryConfig::default(),
+            )
+            .with_store(Some(store))
+            .start()
+        };
+
+        let before = fragment(store.clone());
+        before.send(entry(at(0, 5))).await.unwrap();
+        before.send(leave(at(90, 10))).await.unwrap();
+        before.send(leave(at(85, 2))).await.unwrap();
+
+        let after = fragment(store);
+        let stats = after.send(FragmentStatusRequest).await.unwrap();
+        assert_eq!(stats.time, Some(85));
+        assert_eq!(stats.windows[0].count, 1);
+    }
+
+    /// Counts the samples saved into a memory store.
+    #[derive(Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        saves: AtomicUsize,
+    }
+
+    impl SampleStore for CountingStore {
+        fn save_sample(&self, record: &SampleRecord) -> io::Result<()> {
+            self.saves.fetch_add(1, Ordering::SeqCst);
+            self.inner.save_sample(record)
+        }
+
+        fn load_samples(
+            &self,
+            edge: &Edge,
+            since: Timestamp,
+            limit: usize,
+        ) -> io::Result<Vec<Sample>> {
+            self.inner.load_samples(edge, since, limit)
+        }
+
+        fn edges(&self) -> io::Result<Vec<Edge>> {
+            self.inner.edges()
+        }
+
+        fn save_trajectory(&self, trajectory: &Trajectory) -> io::Result<()> {
+            self.inner.save_trajectory(trajectory)
+        }
+
+        fn samples_between(
+            &self,
+            edge: &Edge,
+            from: Option<Timestamp>,
+            to: Timestamp,
+        ) -> io::Result<Vec<SampleRecord>> {
+            self.inner.samples_between(edge, from, to)
+        }
+
+        fn samples_before(&self, before: Timestamp) -> io::Result<Vec<SampleRecord>> {
+            self.inner.samples_before(before)
+        }
+
+        fn delete_samples_before(&self, before: Timestamp) -> io::Result<usize> {
+            self.inner.delete_samples_before(before)
+        }
+
+        fn delete_sample(&self, record: &SampleRecord) -> io::Result<()> {
+            self.inner.delete_sample(record)
+        }
+
+        fn save_aggregates(&self, aggregates: &[Aggregate]) -> io::Result<()> {
+            self.inner.save_aggregates(aggregates)
+        }
+
+        fn aggregates_between(
+            &self,
+            edge: &Edge,
+            from: Timestamp,
+            to: Timestamp,
+        ) -> io::Result<Vec<Aggregate>> {
+            self.inner.aggregates_between(edge, from, to)
+        }
+
+        fn delete_aggregates_before(&self, before: Timestamp) -> io::Result<usize> {
+            self.inner.delete_aggregates_before(before)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn unchanged_reports_are_not_stored_again() {
+        let store = Arc::new(CountingStore::default());
+        let fragment = RouteFragment::new(
+            Edge::new("1", "2"),
+            Arc::new(SimulatedClock::new(at(0, 0).0)),
+            HistoryConfig::default(),
+        )
+        .with_store(Some(store.clone()))
+        .start();
+
+        fragment.send(entry(at(0, 5))).await.unwrap();
+        fragment.send(leave(at(90, 10))).await.unwrap();
+        assert_eq!(store.saves.load(Ordering::SeqCst), 1);
+
+        fragment.send(leave(at(90, 10))).await.unwrap();
+        fragment.send(entry(at(0, 5))).await.unwrap();
+        assert_eq!(store.saves.load(Ordering::SeqCst), 1);
+
+        fragment.send(leave(at(85, 2))).await.unwrap();
+        assert_eq!(store.saves.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn unpaired_trips_expire() {
+        let (start, _) = at(0, 0);
+        let clock = Arc::new(SimulatedClock::new(start));
+        let fragment =
+            RouteFragment::new(Edge::new("1", "2"), clock.clone(), HistoryConfig::default())
+                .start();
+
+        fragment.send(entry(at(0, 0))).await.unwrap();
+        let (time, age_secs) = at(60, 0);
+        fragment
+            .send(FragmentLeaveEvent {
+                trip: TripKey::new("2", &time),
+                ..leave((time, age_secs))
+            })
+            .await
+            .unwrap();
+
+        let stats = fragment.send(FragmentStatusRequest).await.unwrap();
+        assert_eq!(stats.active_trips, vec!["1"]);
+
+        clock.set(at(ORPHAN_TTL_SECS + 60, 0).0);
+        let stats = fragment.send(FragmentStatusRequest).await.unwrap();
+        assert!(stats.active_trips.is_empty());
+        assert_eq!(stats.orphans.never_left, 1);
+        assert_eq!(stats.orphans.never_entered, 1);
+    }
+
+    #[actix_rt::test]
+    async fn samples_are_grouped_by_line_and_paged() {
+        let fragment = RouteFragment::new(
+            Edge::new("1", "2"),
+            Arc::new(SimulatedClock::new(at(0, 0).0)),
+            HistoryConfig::default(),
+        )
+        .start();
+        let line = |route: &str| {
+            Some(Line {
+                route: String::from(route),
+                direction: String::from("Czerwone Maki"),
+            })
+        };
+
+        for (trip, route, secs) in &[("1", "18", 60), ("2", "52", 100), ("3", "18", 80)] {
+            let (start, _) = at(0, 0);
+            let (stop, _) = at(*secs, 0);
+            fragment
+                .send(FragmentEntryEvent {
+                    trip: TripKey::new(trip, &start),
+                    line: line(route),
+                    ..entry((start, 0))
+                })
+                .await
+                .unwrap();
+            fragment
+                .send(FragmentLeaveEvent {
+                    trip: TripKey::new(trip, &stop),
+                    ..leave((stop, 0))
+                })
+                .await
+                .unwrap();
+        }
+
+        let lines = fragment.send(LineStatsRequest).await.unwrap();
+        let summary: Vec<_> = lines
+            .iter()
+            .map(|l| {
+                (
+                    l.line.as_ref().unwrap().route.as_str(),
+                    l.samples,
+                    l.mean_secs,
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![("18", 2, Some(70)), ("52", 1, Some(100))]);
+
+        let filter = LineFilter {
+            line: Some(String::from("52")),
+            direction: None,
+        };
+        let stats = fragment.send(FilteredStatusRequest(filter)).await.unwrap();
+        assert_eq!(stats.time, Some(100));
+        let counts: Vec<_> = stats.windows.iter().map(|w| w.count).collect();
+        assert_eq!(counts, vec![1, 1, 1]);
+
+        let page = fragment
+            .send(SamplesRequest {
+                from: None,
+                to: None,
+                after: None,
+                limit: 2,
+            })
+            .await
+            .unwrap();
+        let trips: Vec<_> = page.samples.iter().map(|s| s.trip_id.as_str()).collect();
+        assert_eq!(trips, vec!["1", "3"]);
+        assert_eq!(page.samples[0].source, Source::Stop);
+        let next = page.next.unwrap().to_string();
+        assert_eq!(next.parse(), Ok(page.next.unwrap()));
+
+        let page = fragment
+            .send(SamplesRequest {
+                from: None,
+                to: None,
+                after: next.parse().ok(),
+                limit: 2,
+            })
+            .await
+            .unwrap();
+        assert_eq!(page.samples.len(), 1);
+        assert_eq!(page.samples[0].trip_id, "2");
+        assert_eq!(page.next, None);
+    }
+
+    #[actix_rt::test]
+    async fn samples_sharing_a_leave_time_are_paged_once() {
+        let fragment = RouteFragment::new(
+            Edge::new("1", "2"),
+            Arc::new(SimulatedClock::new(at(0, 0).0)),
+            HistoryConfig::default(),
+        )
+        .start();
+        for trip in &["1", "2", "3"] {
+            let (start, _) = at(0, 0);
+            let (stop, _) = at(60, 0);
+            fragment
+                .send(FragmentEntryEvent {
+                    trip: TripKey::new(trip, &start),
+                    ..entry((start, 0))
+                })
+                .await
+                .unwrap();
+            fragment
+                .send(FragmentLeaveEvent {
+                    trip: TripKey::new(trip, &stop),
+                    ..leave((stop, 0))
+                })
+                .await
+                .unwrap();
+        }
+
+        let mut trips = Vec::new();
+        let mut after = None;
+        for _ in 0..4 {
+            let page = fragment
+                .send(SamplesRequest {
+                    from: None,
+                    to: None,
+                    after,
+                    limit: 1,
+                })
+                .await
+                .unwrap();
+            trips.extend(page.samples.into_iter().map(|s| s.trip_id));
+            after = page.next;
+            if after.is_none() {
+                break;
+            }
+        }
+        assert_eq!(trips, vec!["1", "2", "3"]);
+        assert_eq!(after, None);
+    }
+}
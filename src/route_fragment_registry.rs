@@ -1,56 +1,767 @@
 use actix::prelude::*;
+use futures::future;
+use serde::{Deserialize, Serialize};
 
-#[path = "route_fragment.rs"]
-pub mod route_fragment;
+use crate::clock;
+use crate::clock::SharedClock;
+use crate::history::HistoryConfig;
+use crate::network::Network;
+use crate::route_fragment;
+use crate::route_fragment::{Edge, Line, Source};
+use crate::snapshot::{self, Snapshot, SnapshotConfig};
+use crate::storage::SharedStore;
+use crate::timestamp::{self, Timestamp, TripKey};
 
 use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+/// Departures of a trip are kept this long after its last one, so that late
+/// reports still land in the right fragments.
+const ROUTE_MEMORY_SECS: i64 = 3 * 3600;
 
-#[derive(Default)]
 pub struct RouteFragmentRegistry {
-    route_fragments: HashMap<String, Addr<route_fragment::RouteFragment>>,
+    clock: SharedClock,
+    route_fragments: HashMap<Edge, Addr<route_fragment::RouteFragment>>,
+    network: Network,
+    history: HistoryConfig,
+    store: Option<SharedStore>,
+    snapshot: SnapshotConfig,
+    routes: HashMap<(Source, TripKey), Vec<Departed>>,
+}
+
+impl RouteFragmentRegistry {
+    pub fn new(clock: SharedClock) -> RouteFragmentRegistry {
+        RouteFragmentRegistry {
+            clock,
+            route_fragments: HashMap::new(),
+            network: Network::default(),
+            history: HistoryConfig::default(),
+            store: None,
+            snapshot: SnapshotConfig::default(),
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn with_network(mut self, network: Network) -> RouteFragmentRegistry {
+        self.network = network;
+        self
+    }
+
+    pub fn with_history(mut self, history: HistoryConfig) -> RouteFragmentRegistry {
+        self.history = history;
+        self
+    }
+
+    pub fn with_store(mut self, store: Option<SharedStore>) -> RouteFragmentRegistry {
+        self.store = store;
+        self
+    }
+
+    pub fn with_snapshot(mut self, snapshot: SnapshotConfig) -> RouteFragmentRegistry {
+        self.snapshot = snapshot;
+        self
+    }
+}
+
+impl Default for RouteFragmentRegistry {
+    fn default() -> RouteFragmentRegistry {
+        RouteFragmentRegistry::new(clock::system())
+    }
 }
 
 impl Actor for RouteFragmentRegistry {
     type Context = Context<RouteFragmentRegistry>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.restore_fragments();
+        if self.snapshot.path.is_some() {
+            self.restore_snapshot();
+            ctx.run_interval(
+                Duration::from_secs(self.snapshot.interval_secs),
+                |_, ctx| ctx.notify(SaveSnapshot),
+            );
+        }
+        ctx.run_interval(Duration::from_secs(600), |registry, _| {
+            let forget_before =
+                registry.clock.now() - chrono::Duration::seconds(ROUTE_MEMORY_SECS);
+            registry.routes.retain(
+                |_, route| matches!(route.last(), Some(last) if last.departure.time > forget_before),
+            );
+        });
+    }
 }
 
 impl Supervised for RouteFragmentRegistry {}
 impl ArbiterService for RouteFragmentRegistry {}
 
+/// Trip seen departing a stop. Consecutive departures of a trip make up the
+/// fragments, so that branching lines are measured separately.
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[rtype(result = "()")]
+pub struct Departure {
+    pub stop_id: String,
+    pub stop_name: Option<String>,
+    /// Next stop of the trip, when known before the trip gets there.
+    pub next_stop_id: Option<String>,
+    pub trip: TripKey,
+    pub source: Source,
+    pub passage: String,
+    pub age_secs: u64,
+    pub line: Option<Line>,
+    pub vehicle_id: Option<String>,
+    #[serde(
+        serialize_with = "timestamp::serialize",
+        deserialize_with = "timestamp::deserialize"
+    )]
+    pub time: Timestamp,
+}
+
+/// Name to show for a stop, overriding names learned from departures.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StopName {
+    pub id: String,
+    pub name: String,
+}
+
 #[derive(Message)]
-#[rtype(result = "Addr<route_fragment::RouteFragment>")]
-pub struct GetRouteFragment {
-    id: String,
+#[rtype(result = "Vec<(Edge, Addr<route_fragment::RouteFragment>)>")]
+pub struct ListRouteFragments;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Departed {
+    departure: Departure,
+    /// Stop the fragment entry was reported towards.
+    entered: Option<String>,
 }
 
-impl GetRouteFragment {
-    pub fn new(id: String) -> GetRouteFragment {
-        GetRouteFragment { id: id }
+impl RouteFragmentRegistry {
+    fn create_fragment(&mut self, edge: Edge) -> Addr<route_fragment::RouteFragment> {
+        let clock = self.clock.clone();
+        let fragment_edge = edge.clone();
+        let history = self.history.clone();
+        let store = self.store.clone();
+        let new_fragment = route_fragment::RouteFragment::create(|_| {
+            route_fragment::RouteFragment::new(fragment_edge, clock, history).with_store(store)
+        });
+
+        if let Some(name) = self.network.stop_name(&edge.from) {
+            new_fragment.do_send(route_fragment::RouteFragment::update_start(name));
+        }
+        if let Some(name) = self.network.stop_name(&edge.to) {
+            new_fragment.do_send(route_fragment::RouteFragment::update_stop(name));
+        }
+
+        self.route_fragments.insert(edge, new_fragment.clone());
+
+        new_fragment
+    }
+
+    /// Brings back fragments measured before a restart, with their samples.
+    fn restore_fragments(&mut self) {
+        let edges = match self.store.as_ref().map(|store| store.edges()) {
+            Some(Ok(edges)) => edges,
+            Some(Err(e)) => {
+                println!("Failed to list stored fragments: {}", e);
+                return;
+            }
+            None => return,
+        };
+
+        // Trips were seen on them before, so withdrawing one does not drop them.
+        for edge in edges {
+            self.network.observe_trip(edge.clone());
+            self.create_fragment(edge);
+        }
+    }
+
+    fn restore_snapshot(&mut self) {
+        let path = match &self.snapshot.path {
+            Some(path) => path,
+            None => return,
+        };
+        let snapshot = match snapshot::read(path) {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => return,
+            Err(e) => {
+                println!("Failed to read snapshot {}: {}", path, e);
+                return;
+            }
+        };
+        let since = self.clock.now() - chrono::Duration::seconds(self.snapshot.max_age_secs as i64);
+
+        for route in snapshot.routes {
+            if matches!(route.departures.last(), Some(last) if last.departure.time > since) {
+                self.routes
+                    .entry((route.source, route.trip))
+                    .or_insert(route.departures);
+            }
+        }
+        for fragment in snapshot.fragments {
+            self.network.add_edge(fragment.edge.clone());
+            self.fragment(fragment.edge.clone())
+                .do_send(route_fragment::RestoreSnapshot {
+                    snapshot: fragment,
+                    since,
+                });
+        }
+
+        println!(
+            "Restored {} trips from snapshot taken at {}",
+            self.routes.len(),
+            snapshot.taken_at
+        );
+    }
+
+    fn fragment(&mut self, edge: Edge) -> Addr<route_fragment::RouteFragment> {
+        match self.route_fragments.get(&edge) {
+            Some(fragment) if fragment.connected() => fragment.clone(),
+            Some(_) => {
+                println!("Route fragment {} died, recreating", edge);
+                self.create_fragment(edge)
+            }
+            None => {
+                println!("Creating new route fragment {}", edge);
+                self.create_fragment(edge)
+            }
+        }
+    }
+
+    /// Departures are dated by service day on their own, so a trip running
+    /// across the start of one is seen under two keys. Keeps the key its
+    /// route was first seen with.
+    fn trip_key(&self, source: Source, trip: TripKey, time: Timestamp) -> TripKey {
+        let near = |route: &Vec<Departed>| {
+            route
+                .iter()
+                .any(|d| (d.departure.time - time).num_seconds().abs() < ROUTE_MEMORY_SECS)
+        };
+
+        [trip.service_date.pred(), trip.service_date.succ()]
+            .iter()
+            .map(|&service_date| TripKey {
+                service_date,
+                trip_id: trip.trip_id.clone(),
+            })
+            .find(
+                |key| matches!(self.routes.get(&(source, key.clone())), Some(route) if near(route)),
+            )
+            .unwrap_or(trip)
+    }
+
+    /// Takes back the pairing of a trip across the stop it was just seen
+    /// departing in between. Fragments seen with no other trip are dropped.
+    fn withdraw_trip(&mut self, edge: Edge, departure: &Departure) {
+        let retire = self.network.unobserve_trip(&edge);
+        let fragment = if retire {
+            self.route_fragments.remove(&edge)
+        } else {
+            self.route_fragments.get(&edge).cloned()
+        };
+
+        if let Some(fragment) = fragment {
+            fragment.do_send(route_fragment::WithdrawTrip {
+                trip: departure.trip.clone(),
+                source: departure.source,
+                retire,
+            });
+        }
+    }
+
+    fn send_entry(&mut self, to: &str, departure: &Departure) {
+        let edge = Edge::new(&departure.stop_id, to);
+        self.fragment(edge)
+            .do_send(route_fragment::FragmentEntryEvent {
+                trip: departure.trip.clone(),
+                source: departure.source,
+                passage: departure.passage.clone(),
+                age_secs: departure.age_secs,
+                line: departure.line.clone(),
+                vehicle_id: departure.vehicle_id.clone(),
+                time: departure.time,
+            });
+    }
+
+    fn send_leave(&mut self, from: &str, departure: &Departure) {
+        let edge = Edge::new(from, &departure.stop_id);
+        self.fragment(edge)
+            .do_send(route_fragment::FragmentLeaveEvent {
+                trip: departure.trip.clone(),
+                source: departure.source,
+                passage: departure.passage.clone(),
+                age_secs: departure.age_secs,
+                line: departure.line.clone(),
+                vehicle_id: departure.vehicle_id.clone(),
+                time: departure.time,
+            });
     }
 }
 
-impl Handler<GetRouteFragment> for RouteFragmentRegistry {
-    type Result = Addr<route_fragment::RouteFragment>;
+impl Handler<Departure> for RouteFragmentRegistry {
+    type Result = ();
 
-    fn handle(
-        &mut self,
-        _msg: GetRouteFragment,
-        _ctx: &mut Context<Self>,
-    ) -> Addr<route_fragment::RouteFragment> {
-        match self.route_fragments.get(&_msg.id) {
-            Some(trip) => trip.clone(),
+    fn handle(&mut self, mut msg: Departure, _ctx: &mut Context<Self>) {
+        msg.trip = self.trip_key(msg.source, msg.trip, msg.time);
+        let stop = self.network.add_stop(&msg.stop_id);
+        if stop.name.is_none() {
+            stop.name = msg.stop_name.clone();
+        }
+
+        let key = (msg.source, msg.trip.clone());
+        let mut route = self.routes.remove(&key).unwrap_or_default();
+
+        match route
+            .iter()
+            .position(|d| d.departure.stop_id == msg.stop_id)
+        {
+            Some(i) => {
+                // Reported again, the fragments keep the better estimate.
+                if let Some(prev) = i
+                    .checked_sub(1)
+                    .map(|prev| route[prev].departure.stop_id.clone())
+                {
+                    self.send_leave(&prev, &msg);
+                }
+                if let Some(next) = route[i].entered.clone() {
+                    self.send_entry(&next, &msg);
+                }
+                if msg.age_secs < route[i].departure.age_secs {
+                    route[i].departure = msg;
+                }
+            }
             None => {
-                println!("Creating new route fragment {}", _msg.id);
+                let i = route
+                    .iter()
+                    .position(|d| d.departure.time > msg.time)
+                    .unwrap_or(route.len());
 
-                let id = String::from(&_msg.id);
-                let new_fragment = route_fragment::RouteFragment::create(|_| {
-                    route_fragment::RouteFragment::new(String::from(_msg.id))
-                });
+                if let (Some(prev), Some(next)) =
+                    (i.checked_sub(1).map(|p| &route[p]), route.get(i))
+                {
+                    if prev.entered.as_ref() == Some(&next.departure.stop_id) {
+                        let edge = Edge::new(&prev.departure.stop_id, &next.departure.stop_id);
+                        self.withdraw_trip(edge, &msg);
+                    }
+                }
+
+                if let Some(prev) = i.checked_sub(1).map(|prev| &mut route[prev]) {
+                    let prev_departure = prev.departure.clone();
+                    let entered = prev.entered.replace(msg.stop_id.clone());
+                    if entered.as_ref() != Some(&msg.stop_id) {
+                        self.send_entry(&msg.stop_id, &prev_departure);
+                    }
+                    self.send_leave(&prev_departure.stop_id, &msg);
+                    self.network
+                        .observe_trip(Edge::new(&prev_departure.stop_id, &msg.stop_id));
+                }
+
+                let entered = match route.get(i) {
+                    Some(next) => {
+                        let next_departure = next.departure.clone();
+                        self.send_entry(&next_departure.stop_id, &msg);
+                        self.send_leave(&msg.stop_id, &next_departure);
+                        self.network
+                            .observe_trip(Edge::new(&msg.stop_id, &next_departure.stop_id));
+                        Some(next_departure.stop_id)
+                    }
+                    None => {
+                        if let Some(next) = &msg.next_stop_id {
+                            self.send_entry(next, &msg);
+                        }
+                        msg.next_stop_id.clone()
+                    }
+                };
+
+                route.insert(
+                    i,
+                    Departed {
+                        departure: msg,
+                        entered,
+                    },
+                );
+            }
+        }
+
+        self.routes.insert(key, route);
+    }
+}
+
+/// Departures of one trip, as kept in snapshots.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RouteSnapshot {
+    source: Source,
+    trip: TripKey,
+    departures: Vec<Departed>,
+}
+
+/// Writes the pairing state of the registry and its fragments to the
+/// snapshot path, if one is configured.
+#[derive(Message)]
+#[rtype(result = "io::Result<()>")]
+pub struct SaveSnapshot;
+
+impl Handler<SaveSnapshot> for RouteFragmentRegistry {
+    type Result = ResponseFuture<io::Result<()>>;
+
+    fn handle(&mut self, _msg: SaveSnapshot, _ctx: &mut Context<Self>) -> Self::Result {
+        let path = match &self.snapshot.path {
+            Some(path) => path.clone(),
+            None => return Box::pin(async { Ok(()) }),
+        };
+        let taken_at = self.clock.now();
+        let routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|((source, trip), departures)| RouteSnapshot {
+                source: *source,
+                trip: trip.clone(),
+                departures: departures.clone(),
+            })
+            .collect();
+        let requests: Vec<_> = self
+            .route_fragments
+            .values()
+            .map(|fragment| fragment.send(route_fragment::TakeSnapshot))
+            .collect();
 
-                self.route_fragments.insert(id, new_fragment.clone());
+        Box::pin(async move {
+            let fragments = future::join_all(requests)
+                .await
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|fragment| !fragment.is_empty())
+                .collect();
+            let snapshot = Snapshot {
+                taken_at,
+                routes,
+                fragments,
+            };
 
-                new_fragment
+            let result = snapshot::write(&path, &snapshot);
+            if let Err(e) = &result {
+                println!("Failed to write snapshot {}: {}", path, e);
             }
+            result
+        })
+    }
+}
+
+impl Handler<StopName> for RouteFragmentRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: StopName, _ctx: &mut Context<Self>) {
+        if self.network.stop_name(&msg.id) == Some(msg.name.as_str()) {
+            return;
+        }
+
+        for (edge, fragment) in &self.route_fragments {
+            if edge.from == msg.id {
+                fragment.do_send(route_fragment::RouteFragment::update_start(&msg.name));
+            }
+            if edge.to == msg.id {
+                fragment.do_send(route_fragment::RouteFragment::update_stop(&msg.name));
+            }
+        }
+        self.network.name_stop(&msg.id, &msg.name);
+    }
+}
+
+impl Handler<ListRouteFragments> for RouteFragmentRegistry {
+    type Result = MessageResult<ListRouteFragments>;
+
+    fn handle(&mut self, _msg: ListRouteFragments, _ctx: &mut Context<Self>) -> Self::Result {
+        let edges: Vec<_> = self.route_fragments.keys().cloned().collect();
+        MessageResult(
+            edges
+                .into_iter()
+                .map(|edge| (edge.clone(), self.fragment(edge)))
+                .collect(),
+        )
+    }
+}
+
+/// Looks up a fragment without creating it.
+#[derive(Message)]
+#[rtype(result = "Option<Addr<route_fragment::RouteFragment>>")]
+pub struct FindRouteFragment {
+    pub edge: Edge,
+}
+
+impl Handler<FindRouteFragment> for RouteFragmentRegistry {
+    type Result = Option<Addr<route_fragment::RouteFragment>>;
+
+    fn handle(&mut self, msg: FindRouteFragment, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.route_fragments.contains_key(&msg.edge) {
+            Some(self.fragment(msg.edge))
+        } else {
+            None
         }
     }
 }
+
+#[derive(Message)]
+#[rtype(result = "Network")]
+pub struct GetNetwork;
+
+impl Handler<GetNetwork> for RouteFragmentRegistry {
+    type Result = MessageResult<GetNetwork>;
+
+    fn handle(&mut self, _msg: GetNetwork, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.network.clone())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Leg {
+    pub from: String,
+    pub to: String,
+    pub time_secs: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TravelTime {
+    pub stops: Vec<String>,
+    pub legs: Vec<Leg>,
+    /// Sum of the latest leg times, if every leg has been measured.
+    pub time_secs: Option<u64>,
+}
+
+/// Answered with `None` when there is no known path between the stops.
+#[derive(Message)]
+#[rtype(result = "Result<Option<TravelTime>, MailboxError>")]
+pub struct GetTravelTime {
+    pub from: String,
+    pub to: String,
+}
+
+impl Handler<GetTravelTime> for RouteFragmentRegistry {
+    type Result = ResponseFuture<Result<Option<TravelTime>, MailboxError>>;
+
+    fn handle(&mut self, msg: GetTravelTime, _ctx: &mut Context<Self>) -> Self::Result {
+        let stops = match self.network.path(&msg.from, &msg.to) {
+            Some(stops) => stops,
+            None => return Box::pin(async { Ok(None) }),
+        };
+
+        let requests: Vec<_> = stops
+            .windows(2)
+            .map(|pair| {
+                let fragment = self
+                    .route_fragments
+                    .get(&Edge::new(&pair[0], &pair[1]))
+                    .cloned();
+                async move {
+                    match fragment {
+                        Some(fragment) => fragment
+                            .send(route_fragment::FragmentStatusRequest)
+                            .await
+                            .map(|stats| stats.time),
+                        None => Ok(None),
+                    }
+                }
+            })
+            .collect();
+
+        Box::pin(async move {
+            let times = future::join_all(requests)
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let legs: Vec<_> = stops
+                .windows(2)
+                .zip(&times)
+                .map(|(pair, time_secs)| Leg {
+                    from: pair[0].clone(),
+                    to: pair[1].clone(),
+                    time_secs: *time_secs,
+                })
+                .collect();
+            let time_secs = times.iter().copied().sum();
+
+            Ok(Some(TravelTime {
+                stops,
+                legs,
+                time_secs,
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    use crate::clock::SimulatedClock;
+    use crate::route_fragment::FragmentStatusRequest;
+    use crate::storage::MemoryStore;
+    use crate::test_dir::TestDir;
+    use crate::timestamp::TIMEZONE;
+
+    fn departure(trip_id: &str, stop_id: &str, secs: i64) -> Departure {
+        let time = TIMEZONE.ymd(2020, 3, 16).and_hms(12, 0, 0) + chrono::Duration::seconds(secs);
+        Departure {
+            stop_id: String::from(stop_id),
+            stop_name: None,
+            next_stop_id: None,
+            trip: TripKey::new(trip_id, &time),
+            source: Source::Stop,
+            passage: format!("{}@{}", trip_id, stop_id),
+            age_secs: 0,
+            line: None,
+            vehicle_id: None,
+            time,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn branching_trips_measure_separate_fragments() {
+        let clock = std::sync::Arc::new(SimulatedClock::new(departure("1", "A", 0).time));
+        let registry = RouteFragmentRegistry::new(clock).start();
+
+        registry.send(departure("1", "A", 0)).await.unwrap();
+        registry.send(departure("1", "B", 60)).await.unwrap();
+        // Stops are polled independently, so later stops may report first.
+        registry.send(departure("2", "C", 100)).await.unwrap();
+        registry.send(departure("2", "A", 10)).await.unwrap();
+        registry.send(departure("2", "A", 10)).await.unwrap();
+
+        let mut fragments = registry.send(ListRouteFragments).await.unwrap();
+        fragments.sort_by(|a, b| a.0.cmp(&b.0));
+        let edges: Vec<_> = fragments.iter().map(|(edge, _)| edge.to_string()).collect();
+        assert_eq!(edges, vec!["A-B", "A-C"]);
+
+        let mut times = Vec::new();
+        for (_, fragment) in &fragments {
+            times.push(fragment.send(FragmentStatusRequest).await.unwrap().time);
+        }
+        assert_eq!(times, vec![Some(60), Some(90)]);
+
+        let travel = registry
+            .send(GetTravelTime {
+                from: String::from("A"),
+                to: String::from("C"),
+            })
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(travel.stops, vec!["A", "C"]);
+        assert_eq!(travel.time_secs, Some(90));
+    }
+
+    #[actix_rt::test]
+    async fn pairing_across_a_stop_reported_late_is_withdrawn() {
+        let store: SharedStore = std::sync::Arc::new(MemoryStore::default());
+        let clock = std::sync::Arc::new(SimulatedClock::new(departure("1", "A", 0).time));
+        let registry = RouteFragmentRegistry::new(clock)
+            .with_store(Some(store.clone()))
+            .start();
+
+        registry.send(departure("1", "A", 0)).await.unwrap();
+        registry.send(departure("1", "C", 100)).await.unwrap();
+        let shortcut = registry
+            .send(FindRouteFragment {
+                edge: Edge::new("A", "C"),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        registry.send(departure("1", "B", 60)).await.unwrap();
+
+        // Stopped once the trip was withdrawn.
+        assert!(shortcut.send(FragmentStatusRequest).await.is_err());
+        let mut fragments = registry.send(ListRouteFragments).await.unwrap();
+        fragments.sort_by(|a, b| a.0.cmp(&b.0));
+        let edges: Vec<_> = fragments.iter().map(|(edge, _)| edge.to_string()).collect();
+        assert_eq!(edges, vec!["A-B", "B-C"]);
+        for (_, fragment) in &fragments {
+            fragment.send(FragmentStatusRequest).await.unwrap();
+        }
+        assert_eq!(
+            store.edges().unwrap(),
+            vec![Edge::new("A", "B"), Edge::new("B", "C")]
+        );
+
+        let network = registry.send(GetNetwork).await.unwrap();
+        assert_eq!(network.successors("A"), vec!["B"]);
+        assert_eq!(
+            network.path("A", "C"),
+            Some(vec![
+                String::from("A"),
+                String::from("B"),
+                String::from("C")
+            ])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn trips_across_service_day_start_are_paired() {
+        let night = |stop_id: &str, min: u32| {
+            let time = TIMEZONE.ymd(2020, 3, 16).and_hms(3, 0, 0)
+                + chrono::Duration::minutes(min as i64 - 2);
+            Departure {
+                trip: TripKey::new("1", &time),
+                time,
+                ..departure("1", stop_id, 0)
+            }
+        };
+        let (entry, leave) = (night("A", 0), night("B", 4));
+        assert_ne!(entry.trip, leave.trip);
+
+        let clock = std::sync::Arc::new(SimulatedClock::new(entry.time));
+        let registry = RouteFragmentRegistry::new(clock).start();
+        registry.send(entry).await.unwrap();
+        registry.send(leave).await.unwrap();
+
+        let fragment = registry
+            .send(FindRouteFragment {
+                edge: Edge::new("A", "B"),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let stats = fragment.send(FragmentStatusRequest).await.unwrap();
+        assert_eq!(stats.time, Some(240));
+    }
+
+    #[actix_rt::test]
+    async fn trips_in_flight_survive_restart() {
+        let dir = TestDir::new();
+        let snapshot = SnapshotConfig {
+            path: Some(dir.path("snapshot")),
+            ..SnapshotConfig::default()
+        };
+        let registry = |snapshot| {
+            let clock = std::sync::Arc::new(SimulatedClock::new(departure("1", "A", 0).time));
+            RouteFragmentRegistry::new(clock)
+                .with_snapshot(snapshot)
+                .start()
+        };
+
+        let before = registry(snapshot.clone());
+        before
+            .send(Departure {
+                next_stop_id: Some(String::from("B")),
+                ..departure("1", "A", 0)
+            })
+            .await
+            .unwrap();
+        before.send(SaveSnapshot).await.unwrap().unwrap();
+
+        let after = registry(snapshot);
+        after.send(departure("1", "B", 60)).await.unwrap();
+        let fragment = after
+            .send(FindRouteFragment {
+                edge: Edge::new("A", "B"),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let stats = fragment.send(FragmentStatusRequest).await.unwrap();
+        assert_eq!(stats.time, Some(60));
+    }
+}
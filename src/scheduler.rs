@@ -0,0 +1,311 @@
+use actix::prelude::*;
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+/// Request budget shared by all pollers.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// Zero or less disables the rate limit.
+    pub requests_per_second: f64,
+    pub max_in_flight: usize,
+    /// Delay between the first polls of consecutive stops.
+    pub startup_stagger_ms: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> SchedulerConfig {
+        SchedulerConfig {
+            requests_per_second: 2.0,
+            max_in_flight: 4,
+            startup_stagger_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Normal,
+    /// Poll timed for a tram that is about to depart.
+    Imminent,
+}
+
+struct Waiting {
+    priority: Priority,
+    seq: u64,
+    enqueued: Instant,
+    tx: oneshot::Sender<Permit>,
+}
+
+impl Ord for Waiting {
+    fn cmp(&self, other: &Waiting) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiting {
+    fn partial_cmp(&self, other: &Waiting) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiting {
+    fn eq(&self, other: &Waiting) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for Waiting {}
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct SchedulerStats {
+    pub queue_depth: usize,
+    pub queued_imminent: usize,
+    pub in_flight: usize,
+    pub granted: u64,
+    /// Requests that could not start right away.
+    pub throttled: u64,
+    pub mean_wait_ms: u64,
+    pub max_wait_ms: u64,
+}
+
+/// Hands out permits for upstream requests within the configured budget,
+/// imminent polls first.
+pub struct RequestScheduler {
+    config: SchedulerConfig,
+    queue: BinaryHeap<Waiting>,
+    next_seq: u64,
+    in_flight: usize,
+    last_grant: Option<Instant>,
+    wakeup: Option<SpawnHandle>,
+    granted: u64,
+    throttled: u64,
+    total_wait: Duration,
+    max_wait: Duration,
+}
+
+impl RequestScheduler {
+    pub fn new(config: SchedulerConfig) -> RequestScheduler {
+        RequestScheduler {
+            config,
+            queue: BinaryHeap::new(),
+            next_seq: 0,
+            in_flight: 0,
+            last_grant: None,
+            wakeup: None,
+            granted: 0,
+            throttled: 0,
+            total_wait: Duration::default(),
+            max_wait: Duration::default(),
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        if self.config.requests_per_second > 0.0 {
+            Duration::from_secs_f64(1.0 / self.config.requests_per_second)
+        } else {
+            Duration::default()
+        }
+    }
+
+    /// Time left until the rate limit allows another request.
+    fn rate_delay(&self, now: Instant) -> Option<Duration> {
+        let next = self.last_grant? + self.interval();
+        if next > now {
+            Some(next - now)
+        } else {
+            None
+        }
+    }
+
+    fn dispatch(&mut self, ctx: &mut Context<Self>) {
+        while self.in_flight < self.config.max_in_flight.max(1) {
+            match self.queue.peek() {
+                Some(waiting) if waiting.tx.is_canceled() => {
+                    self.queue.pop();
+                    continue;
+                }
+                Some(_) => (),
+                None => break,
+            }
+
+            let now = Instant::now();
+            if let Some(delay) = self.rate_delay(now) {
+                if self.wakeup.is_none() {
+                    self.wakeup = Some(ctx.run_later(delay, |actor, ctx| {
+                        actor.wakeup = None;
+                        actor.dispatch(ctx);
+                    }));
+                }
+                break;
+            }
+
+            let waiting = self.queue.pop().unwrap();
+            let wait = now - waiting.enqueued;
+            self.total_wait += wait;
+            self.max_wait = self.max_wait.max(wait);
+            self.granted += 1;
+            self.in_flight += 1;
+            self.last_grant = Some(now);
+
+            // A permit refused by a gone requester is dropped, which releases it.
+            let _ = waiting.tx.send(Permit {
+                scheduler: ctx.address(),
+            });
+        }
+    }
+}
+
+impl Actor for RequestScheduler {
+    type Context = Context<RequestScheduler>;
+}
+
+/// Slot in the request budget, given back when dropped.
+pub struct Permit {
+    scheduler: Addr<RequestScheduler>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.do_send(Release);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Permit, oneshot::Canceled>")]
+pub struct Acquire {
+    pub priority: Priority,
+}
+
+impl Handler<Acquire> for RequestScheduler {
+    type Result = ResponseFuture<Result<Permit, oneshot::Canceled>>;
+
+    fn handle(&mut self, msg: Acquire, ctx: &mut Context<Self>) -> Self::Result {
+        let now = Instant::now();
+        if !self.queue.is_empty()
+            || self.in_flight >= self.config.max_in_flight.max(1)
+            || self.rate_delay(now).is_some()
+        {
+            self.throttled += 1;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.queue.push(Waiting {
+            priority: msg.priority,
+            seq: self.next_seq,
+            enqueued: now,
+            tx,
+        });
+        self.next_seq += 1;
+        self.dispatch(ctx);
+
+        Box::pin(rx)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Release;
+
+impl Handler<Release> for RequestScheduler {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Release, ctx: &mut Context<Self>) {
+        self.in_flight -= 1;
+        self.dispatch(ctx);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "SchedulerStats")]
+pub struct GetSchedulerStats;
+
+impl Handler<GetSchedulerStats> for RequestScheduler {
+    type Result = MessageResult<GetSchedulerStats>;
+
+    fn handle(&mut self, _msg: GetSchedulerStats, _ctx: &mut Context<Self>) -> Self::Result {
+        let mean_wait = if self.granted > 0 {
+            self.total_wait / self.granted as u32
+        } else {
+            Duration::default()
+        };
+
+        MessageResult(SchedulerStats {
+            queue_depth: self.queue.len(),
+            queued_imminent: self
+                .queue
+                .iter()
+                .filter(|w| w.priority == Priority::Imminent)
+                .count(),
+            in_flight: self.in_flight,
+            granted: self.granted,
+            throttled: self.throttled,
+            mean_wait_ms: mean_wait.as_millis() as u64,
+            max_wait_ms: self.max_wait.as_millis() as u64,
+        })
+    }
+}
+
+/// Waits for a permit; without a reachable scheduler the request goes unthrottled.
+pub async fn acquire(scheduler: &Addr<RequestScheduler>, priority: Priority) -> Option<Permit> {
+    scheduler
+        .send(Acquire { priority })
+        .await
+        .ok()
+        .and_then(Result::ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(requests_per_second: f64, max_in_flight: usize) -> SchedulerConfig {
+        SchedulerConfig {
+            requests_per_second,
+            max_in_flight,
+            startup_stagger_ms: 0,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn in_flight_budget_prefers_imminent_requests() {
+        let scheduler = RequestScheduler::new(config(0.0, 1)).start();
+
+        let first = acquire(&scheduler, Priority::Normal).await.unwrap();
+        let normal = scheduler.send(Acquire {
+            priority: Priority::Normal,
+        });
+        let imminent = scheduler.send(Acquire {
+            priority: Priority::Imminent,
+        });
+        let stats = scheduler.send(GetSchedulerStats).await.unwrap();
+        assert_eq!((stats.queue_depth, stats.queued_imminent), (2, 1));
+        assert_eq!(stats.in_flight, 1);
+
+        drop(first);
+        let granted = futures::future::select(normal, imminent).await;
+        assert!(matches!(granted, futures::future::Either::Right(_)));
+
+        let stats = scheduler.send(GetSchedulerStats).await.unwrap();
+        assert_eq!(stats.throttled, 2);
+        assert_eq!(stats.granted, 2);
+    }
+
+    #[actix_rt::test]
+    async fn requests_are_spaced_by_rate_limit() {
+        let scheduler = RequestScheduler::new(config(20.0, 10)).start();
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            acquire(&scheduler, Priority::Normal).await.unwrap();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
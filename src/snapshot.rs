@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+use crate::route_fragment::FragmentSnapshot;
+use crate::route_fragment_registry::RouteSnapshot;
+use crate::timestamp::{self, Timestamp};
+
+use std::fs;
+use std::io;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// Trips in flight are written here periodically and on shutdown, and
+    /// read back on start.
+    pub path: Option<String>,
+    pub interval_secs: u64,
+    /// Trips last seen longer ago than this are not restored.
+    pub max_age_secs: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> SnapshotConfig {
+        SnapshotConfig {
+            path: None,
+            interval_secs: 60,
+            max_age_secs: 1800,
+        }
+    }
+}
+
+/// Pairing state of the registry and its fragments.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    #[serde(
+        serialize_with = "timestamp::serialize",
+        deserialize_with = "timestamp::deserialize"
+    )]
+    pub taken_at: Timestamp,
+    pub routes: Vec<RouteSnapshot>,
+    pub fragments: Vec<FragmentSnapshot>,
+}
+
+/// Replaces the snapshot at `path` at once, so that a crash mid-write
+/// leaves the previous one in place.
+pub fn write(path: &str, snapshot: &Snapshot) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, serde_json::to_vec(snapshot)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+pub fn read(path: &str) -> io::Result<Option<Snapshot>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,410 @@
+use rusqlite::{params, Connection, Row};
+
+use crate::retention::Aggregate;
+use crate::route_fragment::{Edge, Sample, Source};
+use crate::storage::{SampleRecord, SampleStore};
+use crate::timestamp::Timestamp;
+use crate::trajectory::Trajectory;
+
+use std::io;
+use std::sync::Mutex;
+
+/// Schema changes, applied in order. The number of applied migrations is
+/// kept in the database's `user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE samples (
+        from_stop TEXT NOT NULL,
+        to_stop TEXT NOT NULL,
+        source TEXT NOT NULL,
+        service_date TEXT NOT NULL,
+        trip_id TEXT NOT NULL,
+        vehicle_id TEXT,
+        route TEXT,
+        direction TEXT,
+        entry_time INTEGER NOT NULL,
+        leave_time INTEGER NOT NULL,
+        duration_secs INTEGER NOT NULL,
+        uncertainty_secs INTEGER NOT NULL,
+        PRIMARY KEY (from_stop, to_stop, source, service_date, trip_id)
+    );
+    CREATE INDEX samples_by_leave_time ON samples (from_stop, to_stop, leave_time);",
+    "CREATE TABLE trajectories (
+        id INTEGER PRIMARY KEY,
+        trip_id TEXT NOT NULL,
+        route_name TEXT,
+        direction TEXT,
+        vehicle_id TEXT,
+        started_at INTEGER,
+        finished_at INTEGER,
+        stops TEXT NOT NULL
+    );
+    CREATE INDEX trajectories_by_trip ON trajectories (trip_id);",
+    // Lines are stored as empty strings when unknown, as NULLs are never
+    // equal within a primary key.
+    "CREATE TABLE aggregates (
+        from_stop TEXT NOT NULL,
+        to_stop TEXT NOT NULL,
+        route TEXT NOT NULL,
+        direction TEXT NOT NULL,
+        bucket_start INTEGER NOT NULL,
+        bucket_secs INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        mean_secs REAL NOT NULL,
+        min_secs INTEGER NOT NULL,
+        p10_secs INTEGER NOT NULL,
+        median_secs INTEGER NOT NULL,
+        p90_secs INTEGER NOT NULL,
+        max_secs INTEGER NOT NULL,
+        PRIMARY KEY (from_stop, to_stop, route, direction, bucket_start)
+    );
+    CREATE INDEX aggregates_by_bucket ON aggregates (bucket_start);",
+];
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> io::Result<SqliteStore> {
+        let mut conn = Connection::open(path).map_err(io::Error::other)?;
+        migrate(&mut conn).map_err(io::Error::other)?;
+
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl SampleStore for SqliteStore {
+    fn save_sample(&self, record: &SampleRecord) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO samples (from_stop, to_stop, source, service_date,
+                    trip_id, vehicle_id, route, direction, entry_time, leave_time,
+                    duration_secs, uncertainty_secs)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    record.from_stop,
+                    record.to_stop,
+                    source_name(record.source),
+                    record.service_date.to_string(),
+                    record.trip_id,
+                    record.vehicle_id,
+                    record.route,
+                    record.direction,
+                    record.entry_time,
+                    record.leave_time,
+                    record.duration_secs as i64,
+                    record.uncertainty_secs as i64,
+                ],
+            )
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn load_samples(&self, edge: &Edge, since: Timestamp, limit: usize) -> io::Result<Vec<Sample>> {
+        let conn = self.conn.lock().unwrap();
+        let query = || -> rusqlite::Result<Vec<SampleRecord>> {
+            let mut stmt = conn.prepare(
+                "SELECT * FROM samples
+                 WHERE from_stop = ?1 AND to_stop = ?2 AND leave_time > ?3
+                 ORDER BY leave_time DESC
+                 LIMIT ?4",
+            )?;
+            let rows = stmt.query_map(
+                params![edge.from, edge.to, since.timestamp(), limit as i64],
+                record_from_row,
+            )?;
+            rows.collect()
+        };
+
+        let mut samples: Vec<_> = query()
+            .map_err(io::Error::other)?
+            .iter()
+            .map(SampleRecord::to_sample)
+            .collect();
+        samples.reverse();
+
+        Ok(samples)
+    }
+
+    fn edges(&self) -> io::Result<Vec<Edge>> {
+        let conn = self.conn.lock().unwrap();
+        let query = || -> rusqlite::Result<Vec<Edge>> {
+            let mut stmt =
+                conn.prepare("SELECT DISTINCT from_stop, to_stop FROM samples ORDER BY 1, 2")?;
+            let rows = stmt.query_map(params![], |row| {
+                Ok(Edge {
+                    from: row.get(0)?,
+                    to: row.get(1)?,
+                })
+            })?;
+            rows.collect()
+        };
+
+        query().map_err(io::Error::other)
+    }
+
+    fn save_trajectory(&self, trajectory: &Trajectory) -> io::Result<()> {
+        let times = trajectory.stops.iter().filter_map(|s| s.actual_time);
+        let started_at = times.clone().min().map(|t| t.timestamp());
+        let finished_at = times.max().map(|t| t.timestamp());
+        let stops = serde_json::to_string(&trajectory.stops)?;
+
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO trajectories (trip_id, route_name, direction, vehicle_id,
+                    started_at, finished_at, stops)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    trajectory.trip_id,
+                    trajectory.route_name,
+                    trajectory.direction,
+                    trajectory.vehicle_id,
+                    started_at,
+                    finished_at,
+                    stops,
+                ],
+            )
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn samples_between(
+        &self,
+        edge: &Edge,
+        from: Option<Timestamp>,
+        to: Timestamp,
+    ) -> io::Result<Vec<SampleRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let query = || -> rusqlite::Result<Vec<SampleRecord>> {
+            let mut stmt = conn.prepare(
+                "SELECT * FROM samples
+                 WHERE from_stop = ?1 AND to_stop = ?2 AND leave_time >= ?3 AND leave_time < ?4
+                 ORDER BY leave_time",
+            )?;
+            let rows = stmt.query_map(
+                params![
+                    edge.from,
+                    edge.to,
+                    from.map_or(i64::MIN, |from| from.timestamp()),
+                    to.timestamp()
+                ],
+                record_from_row,
+            )?;
+            rows.collect()
+        };
+
+        query().map_err(io::Error::other)
+    }
+
+    fn samples_before(&self, before: Timestamp) -> io::Result<Vec<SampleRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let query = || -> rusqlite::Result<Vec<SampleRecord>> {
+            let mut stmt =
+                conn.prepare("SELECT * FROM samples WHERE leave_time < ?1 ORDER BY leave_time")?;
+            let rows = stmt.query_map(params![before.timestamp()], record_from_row)?;
+            rows.collect()
+        };
+
+        query().map_err(io::Error::other)
+    }
+
+    fn delete_samples_before(&self, before: Timestamp) -> io::Result<usize> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM samples WHERE leave_time < ?1",
+                params![before.timestamp()],
+            )
+            .map_err(io::Error::other)
+    }
+
+    fn delete_sample(&self, record: &SampleRecord) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM samples WHERE from_stop = ?1 AND to_stop = ?2 AND source = ?3
+                    AND service_date = ?4 AND trip_id = ?5",
+                params![
+                    record.from_stop,
+                    record.to_stop,
+                    source_name(record.source),
+                    record.service_date.to_string(),
+                    record.trip_id
+                ],
+            )
+            .map(|_| ())
+            .map_err(io::Error::other)
+    }
+
+    fn save_aggregates(&self, aggregates: &[Aggregate]) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut save = || -> rusqlite::Result<()> {
+            let tx = conn.transaction()?;
+            for a in aggregates {
+                tx.execute(
+                    "INSERT OR REPLACE INTO aggregates (from_stop, to_stop, route, direction,
+                        bucket_start, bucket_secs, count, mean_secs, min_secs, p10_secs,
+                        median_secs, p90_secs, max_secs)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                    params![
+                        a.from_stop,
+                        a.to_stop,
+                        a.route.as_deref().unwrap_or(""),
+                        a.direction.as_deref().unwrap_or(""),
+                        a.bucket_start,
+                        a.bucket_secs as i64,
+                        a.count as i64,
+                        a.mean_secs,
+                        a.min_secs as i64,
+                        a.p10_secs as i64,
+                        a.median_secs as i64,
+                        a.p90_secs as i64,
+                        a.max_secs as i64,
+                    ],
+                )?;
+            }
+            tx.commit()
+        };
+
+        save().map_err(io::Error::other)
+    }
+
+    fn aggregates_between(
+        &self,
+        edge: &Edge,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> io::Result<Vec<Aggregate>> {
+        let conn = self.conn.lock().unwrap();
+        let query = || -> rusqlite::Result<Vec<Aggregate>> {
+            let mut stmt = conn.prepare(
+                "SELECT * FROM aggregates
+                 WHERE from_stop = ?1 AND to_stop = ?2 AND bucket_start >= ?3 AND bucket_start < ?4
+                 ORDER BY bucket_start",
+            )?;
+            let rows = stmt.query_map(
+                params![edge.from, edge.to, from.timestamp(), to.timestamp()],
+                aggregate_from_row,
+            )?;
+            rows.collect()
+        };
+
+        query().map_err(io::Error::other)
+    }
+
+    fn delete_aggregates_before(&self, before: Timestamp) -> io::Result<usize> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM aggregates WHERE bucket_start < ?1",
+                params![before.timestamp()],
+            )
+            .map_err(io::Error::other)
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let applied: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", &(version as i64 + 1))?;
+        tx.commit()?;
+        println!("Applied storage migration {}", version + 1);
+    }
+
+    Ok(())
+}
+
+fn record_from_row(row: &Row) -> rusqlite::Result<SampleRecord> {
+    let source: String = row.get("source")?;
+    let service_date: String = row.get("service_date")?;
+
+    Ok(SampleRecord {
+        from_stop: row.get("from_stop")?,
+        to_stop: row.get("to_stop")?,
+        source: match source.as_str() {
+            "trip" => Source::Trip,
+            _ => Source::Stop,
+        },
+        service_date: service_date.parse().map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e))
+        })?,
+        trip_id: row.get("trip_id")?,
+        vehicle_id: row.get("vehicle_id")?,
+        route: row.get("route")?,
+        direction: row.get("direction")?,
+        entry_time: row.get("entry_time")?,
+        leave_time: row.get("leave_time")?,
+        duration_secs: row.get::<_, i64>("duration_secs")? as u64,
+        uncertainty_secs: row.get::<_, i64>("uncertainty_secs")? as u64,
+    })
+}
+
+fn aggregate_from_row(row: &Row) -> rusqlite::Result<Aggregate> {
+    let known = |s: String| if s.is_empty() { None } else { Some(s) };
+
+    Ok(Aggregate {
+        from_stop: row.get("from_stop")?,
+        to_stop: row.get("to_stop")?,
+        route: known(row.get("route")?),
+        direction: known(row.get("direction")?),
+        bucket_start: row.get("bucket_start")?,
+        bucket_secs: row.get::<_, i64>("bucket_secs")? as u64,
+        count: row.get::<_, i64>("count")? as u64,
+        mean_secs: row.get("mean_secs")?,
+        min_secs: row.get::<_, i64>("min_secs")? as u64,
+        p10_secs: row.get::<_, i64>("p10_secs")? as u64,
+        median_secs: row.get::<_, i64>("median_secs")? as u64,
+        p90_secs: row.get::<_, i64>("p90_secs")? as u64,
+        max_secs: row.get::<_, i64>("max_secs")? as u64,
+    })
+}
+
+fn source_name(source: Source) -> &'static str {
+    match source {
+        Source::Stop => "stop",
+        Source::Trip => "trip",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::tests::{check_store, record};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn reopening_keeps_schema_and_rows() {
+        let dir = TestDir::new();
+        let path = &dir.path("store.sqlite");
+
+        check_store(&SqliteStore::open(path).unwrap());
+
+        let store = SqliteStore::open(path).unwrap();
+        let version: i64 = store
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+
+        let since = record("1", 0, 0).to_sample().leave_time;
+        let samples = store.load_samples(&Edge::new("A", "B"), since, 10).unwrap();
+        assert_eq!(samples.len(), 2);
+    }
+}
@@ -0,0 +1,57 @@
+use actix::prelude::*;
+
+use crate::timestamp::Timestamp;
+use crate::{PredictedDeparture, StopState};
+
+use std::collections::HashMap;
+
+/// Lets trips reach the pollers of the stops they are heading to.
+#[derive(Default)]
+pub struct StopRegistry {
+    stops: HashMap<String, Addr<StopState>>,
+}
+
+impl Actor for StopRegistry {
+    type Context = Context<StopRegistry>;
+}
+
+impl Supervised for StopRegistry {}
+impl ArbiterService for StopRegistry {}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RegisterStop {
+    pub id: String,
+    pub addr: Addr<StopState>,
+}
+
+impl Handler<RegisterStop> for StopRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterStop, _ctx: &mut Context<Self>) {
+        self.stops.insert(msg.id, msg.addr);
+    }
+}
+
+/// Departures a trip expects at stops ahead of it.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TripPredictions {
+    pub trip_id: String,
+    pub departures: Vec<(String, Timestamp)>,
+}
+
+impl Handler<TripPredictions> for StopRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: TripPredictions, _ctx: &mut Context<Self>) {
+        for (stop_id, time) in msg.departures {
+            if let Some(stop) = self.stops.get(&stop_id) {
+                stop.do_send(PredictedDeparture {
+                    trip_id: msg.trip_id.clone(),
+                    time,
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use chrono::{NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::file_store::{FileFormat, FileStore};
+use crate::retention::{Aggregate, AggregateKey, RetentionConfig};
+use crate::route_fragment::{Edge, Line, Sample, Source};
+use crate::sqlite_store::SqliteStore;
+use crate::timestamp::{Timestamp, TIMEZONE};
+use crate::trajectory::{Trajectory, TrajectorySink};
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Keeps fragment samples and finished trips across restarts.
+pub trait SampleStore: Send + Sync {
+    /// Saves the sample, replacing an earlier one of the same trip.
+    fn save_sample(&self, record: &SampleRecord) -> io::Result<()>;
+
+    /// Newest samples of the fragment that left after `since`, oldest first.
+    fn load_samples(&self, edge: &Edge, since: Timestamp, limit: usize) -> io::Result<Vec<Sample>>;
+
+    /// Fragments with samples stored.
+    fn edges(&self) -> io::Result<Vec<Edge>>;
+
+    fn save_trajectory(&self, trajectory: &Trajectory) -> io::Result<()>;
+
+    /// Samples of the fragment that left within `[from, to)`, oldest first.
+    fn samples_between(
+        &self,
+        edge: &Edge,
+        from: Option<Timestamp>,
+        to: Timestamp,
+    ) -> io::Result<Vec<SampleRecord>>;
+
+    /// Samples of all fragments that left before `before`, oldest first.
+    fn samples_before(&self, before: Timestamp) -> io::Result<Vec<SampleRecord>>;
+
+    /// Returns the number of samples deleted.
+    fn delete_samples_before(&self, before: Timestamp) -> io::Result<usize>;
+
+    /// Deletes the sample of the record's trip, if stored.
+    fn delete_sample(&self, record: &SampleRecord) -> io::Result<()>;
+
+    /// Saves rollups, replacing earlier ones of the same bucket.
+    fn save_aggregates(&self, aggregates: &[Aggregate]) -> io::Result<()>;
+
+    /// Rollups of the fragment for buckets starting within `[from, to)`.
+    fn aggregates_between(
+        &self,
+        edge: &Edge,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> io::Result<Vec<Aggregate>>;
+
+    /// Returns the number of rollups deleted.
+    fn delete_aggregates_before(&self, before: Timestamp) -> io::Result<usize>;
+}
+
+pub type SharedStore = Arc<dyn SampleStore>;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Kept in memory only, lost on restart.
+    Memory,
+    Sqlite,
+    /// Append-only JSON lines.
+    Jsonl,
+    /// Append-only CSV.
+    Csv,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Nothing is stored when unset.
+    pub backend: Option<Backend>,
+    /// Database file for `sqlite`, directory for `jsonl` and `csv`.
+    pub path: Option<String>,
+    pub retention: RetentionConfig,
+}
+
+impl StorageConfig {
+    pub fn open(&self) -> io::Result<Option<SharedStore>> {
+        let backend = match self.backend {
+            Some(backend) => backend,
+            None => return Ok(None),
+        };
+        let path = || {
+            self.path.as_deref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} storage needs a path", backend),
+                )
+            })
+        };
+
+        let store: SharedStore = match backend {
+            Backend::Memory => Arc::new(MemoryStore::default()),
+            Backend::Sqlite => Arc::new(SqliteStore::open(path()?)?),
+            Backend::Jsonl => Arc::new(FileStore::open(path()?, FileFormat::Jsonl)?),
+            Backend::Csv => Arc::new(FileStore::open(path()?, FileFormat::Csv)?),
+        };
+        println!(
+            "Storing measurements in {:?} storage {}",
+            backend,
+            self.path.as_deref().unwrap_or("")
+        );
+
+        Ok(Some(store))
+    }
+}
+
+/// Sample together with the fragment and trip it was measured for, as
+/// written by the stores.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SampleRecord {
+    pub from_stop: String,
+    pub to_stop: String,
+    pub source: Source,
+    pub service_date: NaiveDate,
+    pub trip_id: String,
+    pub vehicle_id: Option<String>,
+    pub route: Option<String>,
+    pub direction: Option<String>,
+    /// Unix time of departure from the first stop.
+    pub entry_time: i64,
+    /// Unix time of departure from the second stop.
+    pub leave_time: i64,
+    pub duration_secs: u64,
+    pub uncertainty_secs: u64,
+}
+
+pub(crate) type RecordKey = (String, String, Source, NaiveDate, String);
+
+impl SampleRecord {
+    pub fn new(edge: &Edge, service_date: NaiveDate, sample: &Sample) -> SampleRecord {
+        SampleRecord {
+            from_stop: edge.from.clone(),
+            to_stop: edge.to.clone(),
+            source: sample.source,
+            service_date,
+            trip_id: sample.trip_id.clone(),
+            vehicle_id: sample.vehicle_id.clone(),
+            route: sample.line.as_ref().map(|l| l.route.clone()),
+            direction: sample.line.as_ref().map(|l| l.direction.clone()),
+            entry_time: sample.entry_time.timestamp(),
+            leave_time: sample.leave_time.timestamp(),
+            duration_secs: sample.duration.as_secs(),
+            uncertainty_secs: sample.uncertainty_secs,
+        }
+    }
+
+    pub fn edge(&self) -> Edge {
+        Edge::new(&self.from_stop, &self.to_stop)
+    }
+
+    /// Later records of the same trip replace earlier ones.
+    pub(crate) fn key(&self) -> RecordKey {
+        (
+            self.from_stop.clone(),
+            self.to_stop.clone(),
+            self.source,
+            self.service_date,
+            self.trip_id.clone(),
+        )
+    }
+
+    pub fn to_sample(&self) -> Sample {
+        Sample {
+            seq: 0,
+            trip_id: self.trip_id.clone(),
+            vehicle_id: self.vehicle_id.clone(),
+            line: self.route.clone().map(|route| Line {
+                route,
+                direction: self.direction.clone().unwrap_or_default(),
+            }),
+            entry_time: TIMEZONE.timestamp(self.entry_time, 0),
+            leave_time: TIMEZONE.timestamp(self.leave_time, 0),
+            duration: Duration::from_secs(self.duration_secs),
+            source: self.source,
+            uncertainty_secs: self.uncertainty_secs,
+        }
+    }
+}
+
+/// Latest record of each trip, for stores that keep every version.
+pub(crate) fn latest_records(
+    records: impl IntoIterator<Item = SampleRecord>,
+) -> HashMap<RecordKey, SampleRecord> {
+    records.into_iter().map(|r| (r.key(), r)).collect()
+}
+
+/// Newest of the records that belong to the fragment and left after `since`.
+pub(crate) fn newest_samples<'a>(
+    records: impl Iterator<Item = &'a SampleRecord>,
+    edge: &Edge,
+    since: Timestamp,
+    limit: usize,
+) -> Vec<Sample> {
+    let mut records: Vec<_> = records
+        .filter(|r| r.from_stop == edge.from && r.to_stop == edge.to)
+        .filter(|r| r.leave_time > since.timestamp())
+        .collect();
+    records.sort_by_key(|r| r.leave_time);
+
+    let skip = records.len().saturating_sub(limit);
+    records[skip..].iter().map(|r| r.to_sample()).collect()
+}
+
+/// Records of the fragment that left within `[from, to)`, oldest first.
+pub(crate) fn records_between<'a>(
+    records: impl Iterator<Item = &'a SampleRecord>,
+    edge: &Edge,
+    from: Option<Timestamp>,
+    to: Timestamp,
+) -> Vec<SampleRecord> {
+    let mut records: Vec<_> = records
+        .filter(|r| r.from_stop == edge.from && r.to_stop == edge.to)
+        .filter(|r| !matches!(from, Some(from) if r.leave_time < from.timestamp()))
+        .filter(|r| r.leave_time < to.timestamp())
+        .cloned()
+        .collect();
+    records.sort_by_key(|r| r.leave_time);
+
+    records
+}
+
+pub(crate) fn records_before<'a>(
+    records: impl Iterator<Item = &'a SampleRecord>,
+    before: Timestamp,
+) -> Vec<SampleRecord> {
+    let mut records: Vec<_> = records
+        .filter(|r| r.leave_time < before.timestamp())
+        .cloned()
+        .collect();
+    records.sort_by_key(|r| r.leave_time);
+
+    records
+}
+
+pub(crate) fn aggregates_between<'a>(
+    aggregates: impl Iterator<Item = &'a Aggregate>,
+    edge: &Edge,
+    from: Timestamp,
+    to: Timestamp,
+) -> Vec<Aggregate> {
+    let mut aggregates: Vec<_> = aggregates
+        .filter(|a| a.from_stop == edge.from && a.to_stop == edge.to)
+        .filter(|a| a.bucket_start >= from.timestamp() && a.bucket_start < to.timestamp())
+        .cloned()
+        .collect();
+    aggregates.sort_by_key(|a| a.bucket_start);
+
+    aggregates
+}
+
+pub(crate) fn distinct_edges<'a>(records: impl Iterator<Item = &'a SampleRecord>) -> Vec<Edge> {
+    records
+        .map(SampleRecord::edge)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    samples: Mutex<HashMap<RecordKey, SampleRecord>>,
+    aggregates: Mutex<HashMap<AggregateKey, Aggregate>>,
+}
+
+impl SampleStore for MemoryStore {
+    fn save_sample(&self, record: &SampleRecord) -> io::Result<()> {
+        self.samples
+            .lock()
+            .unwrap()
+            .insert(record.key(), record.clone());
+        Ok(())
+    }
+
+    fn load_samples(&self, edge: &Edge, since: Timestamp, limit: usize) -> io::Result<Vec<Sample>> {
+        let samples = self.samples.lock().unwrap();
+        Ok(newest_samples(samples.values(), edge, since, limit))
+    }
+
+    fn edges(&self) -> io::Result<Vec<Edge>> {
+        Ok(distinct_edges(self.samples.lock().unwrap().values()))
+    }
+
+    /// Finished trips are not kept, nothing reads them back.
+    fn save_trajectory(&self, _trajectory: &Trajectory) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn samples_between(
+        &self,
+        edge: &Edge,
+        from: Option<Timestamp>,
+        to: Timestamp,
+    ) -> io::Result<Vec<SampleRecord>> {
+        let samples = self.samples.lock().unwrap();
+        Ok(records_between(samples.values(), edge, from, to))
+    }
+
+    fn samples_before(&self, before: Timestamp) -> io::Result<Vec<SampleRecord>> {
+        let samples = self.samples.lock().unwrap();
+        Ok(records_before(samples.values(), before))
+    }
+
+    fn delete_samples_before(&self, before: Timestamp) -> io::Result<usize> {
+        let mut samples = self.samples.lock().unwrap();
+        let count = samples.len();
+        samples.retain(|_, r| r.leave_time >= before.timestamp());
+        Ok(count - samples.len())
+    }
+
+    fn delete_sample(&self, record: &SampleRecord) -> io::Result<()> {
+        self.samples.lock().unwrap().remove(&record.key());
+        Ok(())
+    }
+
+    fn save_aggregates(&self, aggregates: &[Aggregate]) -> io::Result<()> {
+        let mut stored = self.aggregates.lock().unwrap();
+        for aggregate in aggregates {
+            stored.insert(aggregate.key(), aggregate.clone());
+        }
+        Ok(())
+    }
+
+    fn aggregates_between(
+        &self,
+        edge: &Edge,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> io::Result<Vec<Aggregate>> {
+        let aggregates = self.aggregates.lock().unwrap();
+        Ok(aggregates_between(aggregates.values(), edge, from, to))
+    }
+
+    fn delete_aggregates_before(&self, before: Timestamp) -> io::Result<usize> {
+        let mut aggregates = self.aggregates.lock().unwrap();
+        let count = aggregates.len();
+        aggregates.retain(|_, a| a.bucket_start >= before.timestamp());
+        Ok(count - aggregates.len())
+    }
+}
+
+/// Hands finished trips over to a store.
+pub struct StoreSink(pub SharedStore);
+
+impl TrajectorySink for StoreSink {
+    fn finished(&self, trajectory: &Trajectory) {
+        if let Err(e) = self.0.save_trajectory(trajectory) {
+            println!("Failed to store trip {}: {}", trajectory.trip_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::retention;
+
+    pub fn record(trip_id: &str, leave_secs: i64, duration_secs: u64) -> SampleRecord {
+        let time = TIMEZONE.ymd(2020, 3, 16).and_hms(12, 0, 0);
+        SampleRecord {
+            from_stop: String::from("A"),
+            to_stop: String::from("B"),
+            source: Source::Trip,
+            service_date: time.date().naive_local(),
+            trip_id: String::from(trip_id),
+            vehicle_id: None,
+            route: Some(String::from("4")),
+            direction: Some(String::from("Bronowice Małe")),
+            entry_time: time.timestamp() + leave_secs - duration_secs as i64,
+            leave_time: time.timestamp() + leave_secs,
+            duration_secs,
+            uncertainty_secs: 120,
+        }
+    }
+
+    /// Behaviour every store has to share.
+    pub fn check_store(store: &dyn SampleStore) {
+        let since = TIMEZONE.ymd(2020, 3, 16).and_hms(12, 0, 0);
+        store.save_sample(&record("1", 60, 60)).unwrap();
+        store.save_sample(&record("2", 120, 90)).unwrap();
+        store.save_sample(&record("1", 55, 55)).unwrap();
+        store.save_sample(&record("3", -10, 50)).unwrap();
+
+        assert_eq!(store.edges().unwrap(), vec![Edge::new("A", "B")]);
+
+        let samples = store.load_samples(&Edge::new("A", "B"), since, 10).unwrap();
+        let loaded: Vec<_> = samples
+            .iter()
+            .map(|s| (s.trip_id.as_str(), s.duration.as_secs()))
+            .collect();
+        assert_eq!(loaded, vec![("1", 55), ("2", 90)]);
+        assert_eq!(samples[0].line.as_ref().unwrap().route, "4");
+        assert_eq!(samples[0].source, Source::Trip);
+
+        let newest = store.load_samples(&Edge::new("A", "B"), since, 1).unwrap();
+        assert_eq!(newest[0].trip_id, "2");
+        assert!(store
+            .load_samples(&Edge::new("B", "A"), since, 10)
+            .unwrap()
+            .is_empty());
+
+        let edge = Edge::new("A", "B");
+        let hour = since + chrono::Duration::hours(1);
+        assert_eq!(store.samples_between(&edge, None, since).unwrap().len(), 1);
+        let old = store.samples_before(since).unwrap();
+        assert_eq!(old.len(), 1);
+        assert_eq!(old[0].trip_id, "3");
+        assert_eq!(store.delete_samples_before(since).unwrap(), 1);
+        store.delete_sample(&record("2", 0, 0)).unwrap();
+        assert_eq!(store.samples_between(&edge, None, hour).unwrap().len(), 1);
+        store.save_sample(&record("2", 120, 90)).unwrap();
+        let kept = store.samples_between(&edge, None, hour).unwrap();
+        assert_eq!(kept.len(), 2);
+
+        let aggregates = retention::rollup(&kept, 900);
+        store.save_aggregates(&aggregates).unwrap();
+        store.save_aggregates(&aggregates).unwrap();
+        assert_eq!(
+            store.aggregates_between(&edge, since, hour).unwrap(),
+            aggregates
+        );
+        assert_eq!(store.delete_aggregates_before(hour).unwrap(), 1);
+    }
+
+    #[test]
+    fn memory_store_replaces_samples_of_same_trip() {
+        check_store(&MemoryStore::default());
+    }
+}
@@ -0,0 +1,122 @@
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, NaiveTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use std::fmt;
+
+/// Time zone TTSS reports its clock times in.
+pub const TIMEZONE: Tz = chrono_tz::Europe::Warsaw;
+
+/// Service day starts at this hour, so night trips belong to the day before.
+const SERVICE_DAY_START_HOUR: i64 = 3;
+
+pub type Timestamp = DateTime<Tz>;
+
+pub fn service_date(time: &Timestamp) -> NaiveDate {
+    (time.naive_local() - Duration::hours(SERVICE_DAY_START_HOUR)).date()
+}
+
+/// Start of the service day `time` belongs to.
+pub fn service_day_start(time: &Timestamp) -> Timestamp {
+    let start = service_date(time).and_hms(SERVICE_DAY_START_HOUR as u32, 0, 0);
+
+    TIMEZONE
+        .from_local_datetime(&start)
+        .earliest()
+        .unwrap_or(*time)
+}
+
+/// Places a bare `HH:MM` clock time on the date closest to `reference`.
+///
+/// Returns `None` for clock times skipped by a DST switch.
+pub fn resolve_clock_time(reference: &Timestamp, time: NaiveTime) -> Option<Timestamp> {
+    let reference_local = reference.naive_local();
+    let mut candidate = reference_local.date().and_time(time);
+
+    if candidate - reference_local > Duration::hours(12) {
+        candidate -= Duration::days(1);
+    } else if reference_local - candidate > Duration::hours(12) {
+        candidate += Duration::days(1);
+    }
+
+    TIMEZONE.from_local_datetime(&candidate).earliest()
+}
+
+/// Serializes as RFC 3339 with a numeric offset instead of the zone abbreviation.
+pub fn serialize<S: Serializer>(time: &Timestamp, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.with_timezone(&time.offset().fix()).to_rfc3339())
+}
+
+pub fn serialize_option<S: Serializer>(
+    time: &Option<Timestamp>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serialize(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Reads times written by `serialize`.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+    DateTime::<FixedOffset>::deserialize(deserializer).map(|time| time.with_timezone(&TIMEZONE))
+}
+
+/// Trip ids are reused across days, so trips are told apart by service day too.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TripKey {
+    pub service_date: NaiveDate,
+    pub trip_id: String,
+}
+
+impl TripKey {
+    pub fn new(trip_id: &str, time: &Timestamp) -> TripKey {
+        TripKey {
+            service_date: service_date(time),
+            trip_id: String::from(trip_id),
+        }
+    }
+}
+
+impl fmt::Display for TripKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.service_date, self.trip_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> Timestamp {
+        TIMEZONE.ymd(y, m, d).and_hms(h, min, 0)
+    }
+
+    #[test]
+    fn clock_time_resolves_across_midnight() {
+        let reference = at(2020, 3, 16, 0, 2);
+
+        let resolved = resolve_clock_time(&reference, NaiveTime::from_hms(23, 58, 0)).unwrap();
+        assert_eq!(resolved, at(2020, 3, 15, 23, 58));
+        assert_eq!(reference - resolved, Duration::minutes(4));
+    }
+
+    #[test]
+    fn clock_time_in_dst_gap_is_rejected() {
+        let reference = at(2020, 3, 29, 1, 50);
+
+        assert!(resolve_clock_time(&reference, NaiveTime::from_hms(2, 30, 0)).is_none());
+    }
+
+    #[test]
+    fn night_trips_belong_to_previous_service_day() {
+        assert_eq!(
+            TripKey::new("1", &at(2020, 3, 16, 0, 30)),
+            TripKey::new("1", &at(2020, 3, 15, 23, 30))
+        );
+        assert_ne!(
+            TripKey::new("1", &at(2020, 3, 16, 4, 0)),
+            TripKey::new("1", &at(2020, 3, 15, 23, 30))
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use serde::Serialize;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::passage::PassageStatus;
+use crate::timestamp::{self, Timestamp};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TrajectoryStop {
+    pub stop_id: String,
+    pub stop_name: String,
+    pub seq: u32,
+    #[serde(serialize_with = "timestamp::serialize_option")]
+    pub planned_time: Option<Timestamp>,
+    /// Departure time once departed, prediction before.
+    #[serde(serialize_with = "timestamp::serialize_option")]
+    pub actual_time: Option<Timestamp>,
+    pub status: PassageStatus,
+    pub delay_secs: Option<i64>,
+}
+
+/// Every stop of a trip known so far, in order.
+#[derive(Serialize, Debug, Clone)]
+pub struct Trajectory {
+    pub trip_id: String,
+    pub route_name: Option<String>,
+    pub direction: Option<String>,
+    pub vehicle_id: Option<String>,
+    pub stops: Vec<TrajectoryStop>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TripSummary {
+    pub trip_id: String,
+    pub route_name: Option<String>,
+    pub direction: Option<String>,
+    pub vehicle_id: Option<String>,
+    pub next_stop: Option<String>,
+    /// Delay at the last departed stop.
+    pub delay_secs: Option<i64>,
+}
+
+/// Receives trajectories of retired trips.
+pub trait TrajectorySink: Send + Sync {
+    fn finished(&self, trajectory: &Trajectory);
+}
+
+pub struct LogSink;
+
+impl TrajectorySink for LogSink {
+    fn finished(&self, trajectory: &Trajectory) {
+        println!(
+            "Trip {} finished after {} stops",
+            trajectory.trip_id,
+            trajectory.stops.len()
+        );
+    }
+}
+
+impl TrajectorySink for Vec<Arc<dyn TrajectorySink>> {
+    fn finished(&self, trajectory: &Trajectory) {
+        for sink in self {
+            sink.finished(trajectory);
+        }
+    }
+}
+
+/// Appends trajectories as JSON lines.
+pub struct JsonlSink {
+    file: Mutex<File>,
+}
+
+impl JsonlSink {
+    pub fn open(path: &str) -> io::Result<JsonlSink> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        println!("Writing finished trips to {}", path);
+
+        Ok(JsonlSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl TrajectorySink for JsonlSink {
+    fn finished(&self, trajectory: &Trajectory) {
+        let mut line = match serde_json::to_vec(trajectory) {
+            Ok(line) => line,
+            Err(e) => {
+                println!("Failed to serialize trip {}: {}", trajectory.trip_id, e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            println!("Failed to write trip {}: {}", trajectory.trip_id, e);
+        }
+    }
+}
@@ -1,48 +1,372 @@
 use actix::prelude::*;
+use futures::future;
+use serde::Deserialize;
 
-#[path = "passage.rs"]
-mod passage;
+use crate::clock::SharedClock;
+use crate::passage;
+use crate::storage::{SharedStore, StoreSink};
+use crate::timestamp::{self, Timestamp, TripKey};
+use crate::trajectory::{JsonlSink, LogSink, Trajectory, TrajectorySink, TripSummary};
+use crate::ttss::TtssClient;
 
 use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Retired trip ids are refused for this long, as stops keep reporting
+/// departed trips for a while.
+const RETIRED_MEMORY: Duration = Duration::from_secs(3 * 3600);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TripConfig {
+    pub idle_ttl_secs: u64,
+    /// Finished trips are appended here as JSON lines instead of being logged.
+    pub trajectory_path: Option<String>,
+}
+
+impl Default for TripConfig {
+    fn default() -> TripConfig {
+        TripConfig {
+            idle_ttl_secs: 1800,
+            trajectory_path: None,
+        }
+    }
+}
+
+impl TripConfig {
+    pub fn retirement(&self, store: Option<SharedStore>) -> io::Result<passage::Retirement> {
+        let sink: Arc<dyn TrajectorySink> = match (&self.trajectory_path, store) {
+            (Some(path), Some(store)) => Arc::new(vec![
+                Arc::new(JsonlSink::open(path)?) as Arc<_>,
+                Arc::new(StoreSink(store)) as Arc<_>,
+            ]),
+            (Some(path), None) => Arc::new(JsonlSink::open(path)?),
+            (None, Some(store)) => Arc::new(StoreSink(store)),
+            (None, None) => Arc::new(LogSink),
+        };
+
+        Ok(passage::Retirement {
+            idle_ttl: Duration::from_secs(self.idle_ttl_secs),
+            sink,
+        })
+    }
+}
 
 #[derive(Default)]
 pub struct TripRegistry {
     trips: HashMap<String, Addr<passage::Trip>>,
+    /// When each recently retired trip was retired.
+    retired: HashMap<TripKey, (Instant, Timestamp)>,
+    retirement: passage::Retirement,
+}
+
+impl TripRegistry {
+    pub fn new(retirement: passage::Retirement) -> TripRegistry {
+        TripRegistry {
+            retirement,
+            ..TripRegistry::default()
+        }
+    }
 }
 
 impl Actor for TripRegistry {
     type Context = Context<TripRegistry>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(Duration::from_secs(600), |registry, _| {
+            registry
+                .retired
+                .retain(|_, (retired_at, _)| retired_at.elapsed() < RETIRED_MEMORY);
+        });
+    }
+}
+
+impl TripRegistry {
+    /// Whether the trip seen at `now` was retired recently. A trip running
+    /// across the start of the service day keeps the date it started on.
+    fn is_retired(&self, id: &str, now: &Timestamp) -> bool {
+        let key = TripKey::new(id, now);
+        if self.retired.contains_key(&key) {
+            return true;
+        }
+
+        let previous = TripKey {
+            service_date: key.service_date.pred(),
+            ..key
+        };
+        let day_start = timestamp::service_day_start(now);
+        matches!(self.retired.get(&previous), Some((_, time)) if *time >= day_start)
+    }
 }
 
 impl Supervised for TripRegistry {}
 impl ArbiterService for TripRegistry {}
 
+/// Answered with `None` for recently retired trips.
 #[derive(Message)]
-#[rtype(result = "Addr<passage::Trip>")]
+#[rtype(result = "Option<Addr<passage::Trip>>")]
 pub struct RegisterTrip {
     id: String,
+    client: Option<Arc<dyn TtssClient>>,
+    clock: SharedClock,
+    vehicle_id: Option<String>,
 }
 
 impl RegisterTrip {
-    pub fn new(id: String) -> RegisterTrip {
-        RegisterTrip { id: id }
+    pub fn new(
+        id: String,
+        client: Option<Arc<dyn TtssClient>>,
+        clock: SharedClock,
+    ) -> RegisterTrip {
+        RegisterTrip {
+            id,
+            client,
+            clock,
+            vehicle_id: None,
+        }
+    }
+
+    pub fn with_vehicle(mut self, vehicle_id: Option<String>) -> RegisterTrip {
+        self.vehicle_id = vehicle_id;
+        self
     }
 }
 
 impl Handler<RegisterTrip> for TripRegistry {
-    type Result = Addr<passage::Trip>;
+    type Result = Option<Addr<passage::Trip>>;
+
+    fn handle(
+        &mut self,
+        _msg: RegisterTrip,
+        _ctx: &mut Context<Self>,
+    ) -> Option<Addr<passage::Trip>> {
+        if self.is_retired(&_msg.id, &_msg.clock.now()) {
+            return None;
+        }
 
-    fn handle(&mut self, _msg: RegisterTrip, _ctx: &mut Context<Self>) -> Addr<passage::Trip> {
-        match self.trips.get(&_msg.id) {
-            Some(trip) => trip.clone(),
-            None => {
+        let trip = match self.trips.get(&_msg.id) {
+            Some(trip) if trip.connected() => trip.clone(),
+            _ => {
+                let id = String::from(&_msg.id);
+                let client = _msg.client;
+                let clock = _msg.clock;
+                let retirement = self.retirement.clone();
                 let new_trip =
-                    passage::Trip::create(|_| passage::Trip::new(String::from(&_msg.id)));
+                    Supervisor::start(|_| passage::Trip::new(id, client, clock, retirement));
 
                 self.trips.insert(_msg.id, new_trip.clone());
 
                 new_trip
             }
+        };
+
+        if let Some(vehicle_id) = _msg.vehicle_id {
+            trip.do_send(passage::TripVehicle { vehicle_id });
         }
+
+        Some(trip)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TripRetired {
+    pub id: String,
+    pub key: TripKey,
+    pub time: Timestamp,
+    pub addr: Addr<passage::Trip>,
+}
+
+impl Handler<TripRetired> for TripRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: TripRetired, _ctx: &mut Context<Self>) {
+        if self.trips.get(&msg.id) == Some(&msg.addr) {
+            self.trips.remove(&msg.id);
+        }
+        self.retired.insert(msg.key, (Instant::now(), msg.time));
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Vec<TripSummary>, MailboxError>")]
+pub struct ListTrips;
+
+impl Handler<ListTrips> for TripRegistry {
+    type Result = ResponseFuture<Result<Vec<TripSummary>, MailboxError>>;
+
+    fn handle(&mut self, _msg: ListTrips, _ctx: &mut Context<Self>) -> Self::Result {
+        let requests: Vec<_> = self
+            .trips
+            .values()
+            .map(|trip| trip.send(passage::GetTripSummary))
+            .collect();
+
+        Box::pin(async move {
+            let mut trips: Vec<_> = future::join_all(requests)
+                .await
+                .into_iter()
+                .filter_map(Result::ok)
+                .collect();
+            trips.sort_by(|a, b| (&a.route_name, &a.trip_id).cmp(&(&b.route_name, &b.trip_id)));
+
+            Ok(trips)
+        })
+    }
+}
+
+/// Trajectory of a trip still followed. Retired trips are answered with
+/// `None`; their trajectories are only handed to the sink.
+#[derive(Message)]
+#[rtype(result = "Result<Option<Trajectory>, MailboxError>")]
+pub struct GetTrajectory {
+    pub id: String,
+}
+
+impl Handler<GetTrajectory> for TripRegistry {
+    type Result = ResponseFuture<Result<Option<Trajectory>, MailboxError>>;
+
+    fn handle(&mut self, msg: GetTrajectory, _ctx: &mut Context<Self>) -> Self::Result {
+        let request = self
+            .trips
+            .get(&msg.id)
+            .map(|trip| trip.send(passage::GetTrajectory));
+
+        Box::pin(async move {
+            match request {
+                Some(request) => request.await.map(Some),
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::fake_ttss::{test_scenario, FakeTtss};
+    use crate::passage::DirectPassageSync;
+    use crate::trajectory::Trajectory;
+
+    #[derive(Default)]
+    struct CollectingSink(Mutex<Vec<Trajectory>>);
+
+    impl TrajectorySink for CollectingSink {
+        fn finished(&self, trajectory: &Trajectory) {
+            self.0.lock().unwrap().push(trajectory.clone());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn finished_trip_is_retired_with_its_trajectory() {
+        let sink = Arc::new(CollectingSink::default());
+        let registry = TripRegistry::new(passage::Retirement {
+            idle_ttl: Duration::from_secs(60),
+            sink: sink.clone(),
+        })
+        .start();
+        actix::Registry::set(registry.clone());
+
+        let fake = FakeTtss::new(test_scenario());
+        let trip_id = "8059232507169530113";
+        let register = || RegisterTrip::new(trip_id.to_string(), None, crate::clock::system());
+
+        for &t in &[100, 200] {
+            fake.clock.advance(t - fake.clock.now());
+            let passage = serde_json::from_value(fake.trip_passages(trip_id).unwrap()).unwrap();
+            let trip = registry.send(register()).await.unwrap().unwrap();
+            trip.send(DirectPassageSync { passage }).await.unwrap();
+        }
+
+        assert!(registry.send(register()).await.unwrap().is_none());
+
+        let finished = sink.0.lock().unwrap();
+        assert_eq!(finished.len(), 1);
+        let stops: Vec<_> = finished[0].stops.iter().map(|s| s.seq).collect();
+        assert_eq!(stops, vec![1, 2, 3]);
+        assert_eq!(finished[0].route_name.as_deref(), Some("4"));
+    }
+
+    #[test]
+    fn trip_ids_are_refused_on_the_day_they_retired() {
+        use chrono::TimeZone;
+
+        use crate::timestamp::TIMEZONE;
+
+        let at = |d, h, min| TIMEZONE.ymd(2020, 3, d).and_hms(h, min, 0);
+        let mut registry = TripRegistry::default();
+        let retire = |registry: &mut TripRegistry, id: &str, started, retired| {
+            registry
+                .retired
+                .insert(TripKey::new(id, &started), (Instant::now(), retired));
+        };
+
+        retire(&mut registry, "1", at(16, 12, 0), at(16, 13, 0));
+        assert!(registry.is_retired("1", &at(16, 14, 0)));
+        assert!(!registry.is_retired("1", &at(17, 12, 0)));
+
+        // Started late in the evening and ran past the start of the next
+        // service day.
+        retire(&mut registry, "2", at(17, 2, 30), at(17, 3, 30));
+        assert!(registry.is_retired("2", &at(17, 4, 0)));
+        assert!(!registry.is_retired("2", &at(18, 4, 0)));
+    }
+
+    #[actix_rt::test]
+    async fn trips_api_shows_timeline_of_active_trip() {
+        use actix_web::{test, web, App};
+
+        let registry = TripRegistry::default().start();
+        actix::Registry::set(registry.clone());
+
+        let fake = FakeTtss::new(test_scenario());
+        let trip_id = "8059232507169530113";
+        fake.clock.advance(100);
+        let passage = serde_json::from_value(fake.trip_passages(trip_id).unwrap()).unwrap();
+        let trip = registry
+            .send(
+                RegisterTrip::new(trip_id.to_string(), None, crate::clock::system())
+                    .with_vehicle(Some(String::from("-1188950295589926069"))),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        trip.send(DirectPassageSync { passage }).await.unwrap();
+
+        let mut app = test::init_service(
+            App::new()
+                .data(registry)
+                .service(web::resource("/trips").to(crate::handle_trips))
+                .service(web::resource("/trips/{id}").to(crate::handle_trip)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/trips").to_request();
+        let trips: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(trips[0]["trip_id"], trip_id);
+        assert_eq!(trips[0]["vehicle_id"], "-1188950295589926069");
+        assert_eq!(trips[0]["next_stop"], "Cystersów");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/trips/{}", trip_id))
+            .to_request();
+        let timeline: serde_json::Value = test::read_response_json(&mut app, req).await;
+        let statuses: Vec<_> = timeline["stops"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(statuses, vec!["DEPARTED", "DEPARTED", "PREDICTED"]);
+        assert_eq!(timeline["stops"][0]["delay_secs"], 0);
+
+        let req = test::TestRequest::get().uri("/trips/1").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), 404);
     }
 }
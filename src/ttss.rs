@@ -0,0 +1,294 @@
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::Addr;
+
+use crate::capture::{CaptureRecord, Endpoint, Recorder};
+use crate::passage::PassageWelcome;
+use crate::scheduler::{self, Priority, RequestScheduler, SchedulerConfig};
+use crate::Welcome;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Connection, timeout or body transfer problem.
+    Network(reqwest::Error),
+    /// Upstream answered with a non-success HTTP status.
+    Status(u16),
+    /// Response body is not the JSON we expect.
+    Decode(serde_json::Error),
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Network,
+    Status,
+    Decode,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Network(_) => ErrorKind::Network,
+            Error::Status(_) => ErrorKind::Status,
+            Error::Decode(_) => ErrorKind::Decode,
+        }
+    }
+
+    /// Whether repeating the same request may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Network(_) => true,
+            Error::Status(status) => *status == 429 || *status >= 500,
+            Error::Decode(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "network error: {}", e),
+            Error::Status(status) => write!(f, "HTTP status {}", status),
+            Error::Decode(e) => write!(f, "malformed response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Network(e)
+    }
+}
+
+pub type FetchResult<T> = Result<T, Error>;
+
+/// Source of TTSS passage data.
+pub trait TtssClient: Send + Sync {
+    fn stop_passages(
+        &self,
+        stop_point: &str,
+        priority: Priority,
+    ) -> BoxFuture<'static, FetchResult<Welcome>>;
+
+    fn trip_passages(
+        &self,
+        trip_id: &str,
+        priority: Priority,
+    ) -> BoxFuture<'static, FetchResult<PassageWelcome>>;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub base_url: String,
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+    pub user_agent: String,
+    /// When set, every response is appended to this capture file.
+    pub capture_path: Option<String>,
+    pub retry: RetryConfig,
+    pub scheduler: SchedulerConfig,
+}
+
+/// Jittered exponential backoff for transient fetch errors.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Total number of tries, including the first one.
+    pub attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Delay before retry number `attempt` (0-based), drawn from
+    /// `[0, min(max_delay, base_delay * 2^attempt)]`.
+    fn delay(&self, attempt: u32) -> Duration {
+        let cap = self
+            .base_delay_ms
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay_ms);
+
+        Duration::from_millis(rand::thread_rng().gen_range(0, cap + 1))
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            base_url: String::from("https://mpk.jacekk.net/proxy_tram.php"),
+            timeout_secs: 30,
+            connect_timeout_secs: 10,
+            user_agent: format!("mpkflow/{}", env!("CARGO_PKG_VERSION")),
+            capture_path: None,
+            retry: RetryConfig::default(),
+            scheduler: SchedulerConfig::default(),
+        }
+    }
+}
+
+impl Endpoint {
+    fn path(self) -> &'static str {
+        match self {
+            Endpoint::StopPassages => "/services/passageInfo/stopPassages/stopPoint",
+            Endpoint::TripPassages => "/services/tripInfo/tripPassages",
+        }
+    }
+
+    fn id_param(self) -> &'static str {
+        match self {
+            Endpoint::StopPassages => "stopPoint",
+            Endpoint::TripPassages => "tripId",
+        }
+    }
+}
+
+pub struct ReqwestClient {
+    client: reqwest::Client,
+    base_url: String,
+    retry: RetryConfig,
+    recorder: Option<Arc<Recorder>>,
+    scheduler: Option<Addr<RequestScheduler>>,
+}
+
+impl ReqwestClient {
+    pub fn new(config: &ClientConfig) -> reqwest::Result<ReqwestClient> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+            .user_agent(config.user_agent.as_str())
+            .build()?;
+
+        Ok(ReqwestClient {
+            client,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            retry: config.retry.clone(),
+            recorder: None,
+            scheduler: None,
+        })
+    }
+
+    pub fn with_recorder(mut self, recorder: Recorder) -> ReqwestClient {
+        self.recorder = Some(Arc::new(recorder));
+        self
+    }
+
+    /// Makes every request attempt wait for a permit from `scheduler`.
+    pub fn with_scheduler(mut self, scheduler: Addr<RequestScheduler>) -> ReqwestClient {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    fn get<T>(
+        &self,
+        endpoint: Endpoint,
+        id: &str,
+        priority: Priority,
+    ) -> BoxFuture<'static, FetchResult<T>>
+    where
+        T: serde::de::DeserializeOwned + Send + 'static,
+    {
+        let client = self.client.clone();
+        let url = format!("{}{}", self.base_url, endpoint.path());
+        let retry = self.retry.clone();
+        let recorder = self.recorder.clone();
+        let scheduler = self.scheduler.clone();
+        let id = id.to_string();
+
+        async move {
+            let mut attempt = 0;
+            loop {
+                let permit = match &scheduler {
+                    Some(scheduler) => scheduler::acquire(scheduler, priority).await,
+                    None => None,
+                };
+                println!("fetch {:?} {}", endpoint, id);
+
+                let result = fetch_once(&client, &url, endpoint, &id, recorder.as_deref()).await;
+                drop(permit);
+                match result {
+                    Err(e) if e.is_transient() && attempt + 1 < retry.attempts => {
+                        let delay = retry.delay(attempt);
+                        println!(
+                            "fetch {:?} {} failed: {}, retrying in {:?}",
+                            endpoint, id, e, delay
+                        );
+
+                        tokio::time::delay_for(delay).await;
+                        attempt += 1;
+                    }
+                    result => return result,
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+async fn fetch_once<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+    endpoint: Endpoint,
+    id: &str,
+    recorder: Option<&Recorder>,
+) -> FetchResult<T> {
+    let response = client
+        .get(url)
+        .query(&[(endpoint.id_param(), id), ("mode", "departure")])
+        .send()
+        .await?;
+    let status = response.status();
+    let body = response.text().await?;
+
+    if let Some(recorder) = recorder {
+        recorder.record(&CaptureRecord {
+            fetched_at: chrono::Local::now(),
+            endpoint,
+            id: id.to_string(),
+            status: status.as_u16(),
+            body: body.clone(),
+        });
+    }
+
+    if !status.is_success() {
+        return Err(Error::Status(status.as_u16()));
+    }
+
+    serde_json::from_str(&body).map_err(Error::Decode)
+}
+
+impl TtssClient for ReqwestClient {
+    fn stop_passages(
+        &self,
+        stop_point: &str,
+        priority: Priority,
+    ) -> BoxFuture<'static, FetchResult<Welcome>> {
+        self.get(Endpoint::StopPassages, stop_point, priority)
+    }
+
+    fn trip_passages(
+        &self,
+        trip_id: &str,
+        priority: Priority,
+    ) -> BoxFuture<'static, FetchResult<PassageWelcome>> {
+        self.get(Endpoint::TripPassages, trip_id, priority)
+    }
+}